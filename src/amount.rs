@@ -0,0 +1,153 @@
+//! monetary amounts
+
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// DECIMALS is the number of decimal places of a coin
+pub const DECIMALS: usize = 8;
+/// COIN is the number of smallest units in one coin
+pub const COIN: u64 = 100_000_000;
+/// MAX_MONEY bounds every amount, the total supply included
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+/// Amount is a non-negative quantity of money in smallest units
+///
+/// Arithmetic is checked and never goes beyond MAX_MONEY.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Amount {
+        Amount(coins * COIN)
+    }
+
+    pub fn units(self) -> u64 {
+        self.0
+    }
+
+    /// IsValid checks the amount is within MAX_MONEY
+    pub fn is_valid(self) -> bool {
+        self <= MAX_MONEY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        let sum = Amount(self.0.checked_add(other.0)?);
+        if sum.is_valid() {
+            Some(sum)
+        } else {
+            None
+        }
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        Some(Amount(self.0.checked_sub(other.0)?))
+    }
+
+    /// CheckedSum adds up amounts, failing if any of them or the total exceeds MAX_MONEY
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        let mut total = Amount::ZERO;
+        for amount in amounts {
+            if !amount.is_valid() {
+                return None;
+            }
+            total = total.checked_add(amount)?;
+        }
+        Some(total)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let coins = self.0 / COIN;
+        let units = self.0 % COIN;
+        if units == 0 {
+            return write!(f, "{}", coins);
+        }
+        let decimals = format!("{:0width$}", units, width = DECIMALS);
+        write!(f, "{}.{}", coins, decimals.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = failure::Error;
+
+    /// Parses an amount of coins with up to DECIMALS decimal places, like "1.5"
+    fn from_str(s: &str) -> Result<Amount, Self::Err> {
+        let (coins, decimals) = match s.split_once('.') {
+            Some((coins, decimals)) => (coins, decimals),
+            None => (s, ""),
+        };
+        let is_digits = |p: &str| p.bytes().all(|b| b.is_ascii_digit());
+        if coins.is_empty() || !is_digits(coins) || !is_digits(decimals) {
+            return Err(format_err!("Invalid amount: {}", s));
+        }
+        if decimals.len() > DECIMALS {
+            return Err(format_err!(
+                "Amount has more than {} decimals: {}",
+                DECIMALS,
+                s
+            ));
+        }
+
+        let coins: u64 = coins.parse()?;
+        let units: u64 = format!("{:0<width$}", decimals, width = DECIMALS).parse()?;
+        let amount = coins
+            .checked_mul(COIN)
+            .and_then(|c| c.checked_add(units))
+            .map(Amount)
+            .filter(|a| a.is_valid());
+        amount.ok_or_else(|| format_err!("Amount is larger than the maximum: {}", s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!("10".parse::<Amount>().unwrap(), Amount::from_coins(10));
+        assert_eq!(
+            "1.5".parse::<Amount>().unwrap(),
+            Amount::from_units(150_000_000)
+        );
+        assert_eq!(
+            "0.00000001".parse::<Amount>().unwrap(),
+            Amount::from_units(1)
+        );
+        assert!("0.000000001".parse::<Amount>().is_err());
+        assert!("-1".parse::<Amount>().is_err());
+        assert!(".5".parse::<Amount>().is_err());
+        assert!("21000001".parse::<Amount>().is_err());
+
+        assert_eq!(Amount::from_coins(10).to_string(), "10");
+        assert_eq!(Amount::from_units(150_000_000).to_string(), "1.5");
+        assert_eq!(Amount::from_units(1).to_string(), "0.00000001");
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let one = Amount::from_coins(1);
+        assert_eq!(one.checked_add(one), Some(Amount::from_coins(2)));
+        assert_eq!(MAX_MONEY.checked_add(Amount::from_units(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(one), None);
+        assert_eq!(
+            Amount::checked_sum(vec![one, one, one]),
+            Some(Amount::from_coins(3))
+        );
+        assert_eq!(
+            Amount::checked_sum(vec![one, Amount::from_units(u64::MAX)]),
+            None
+        );
+    }
+}
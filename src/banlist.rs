@@ -0,0 +1,182 @@
+//! banned peers
+//!
+//! A node bans the IP address of a peer whose ban score reaches its threshold, and
+//! neither accepts connections from nor connects to a banned address until the ban
+//! expires. Bans are kept in a db of the data directory that is only open while the
+//! bans are read or changed, so the CLI can change the bans of a running node, which
+//! picks them up the next time it lists them.
+
+use crate::errors::Result;
+use crate::peer::now;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// BANLIST_DB is the db in the data directory where a node keeps its bans
+pub const BANLIST_DB: &str = "banlist";
+/// OPEN_ATTEMPTS is how many times the db is tried while another process has it open
+const OPEN_ATTEMPTS: u32 = 50;
+
+/// Ban keeps an IP address from connecting until a time, in seconds since the Unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub banned_at: u64,
+    pub until: u64,
+    pub reason: String,
+}
+
+/// BanList is the set of banned addresses, kept in a db or, without one, in memory
+///
+/// The bans are cached: IsBanned only reads the db the first time, while List and the
+/// changes read it again to pick up the bans other processes set. The db is only
+/// written when a change leaves the bans different.
+pub struct BanList {
+    path: Option<PathBuf>,
+    /// bans as last read or written, None until the db is first read
+    cache: Mutex<Option<HashMap<IpAddr, Ban>>>,
+}
+
+impl BanList {
+    pub fn new(path: Option<PathBuf>) -> BanList {
+        BanList {
+            path,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// IsBanned returns whether ip is banned, as of the last time the bans were read
+    pub fn is_banned(&self, ip: IpAddr) -> Result<bool> {
+        self.update(false, |bans| bans.contains_key(&ip))
+    }
+
+    /// Ban bans ip for duration, replacing any ban it already has
+    pub fn ban(&self, ip: IpAddr, duration: Duration, reason: &str) -> Result<()> {
+        let banned_at = now();
+        let ban = Ban {
+            ip,
+            banned_at,
+            until: banned_at.saturating_add(duration.as_secs()),
+            reason: reason.to_string(),
+        };
+        self.update(true, |bans| {
+            bans.insert(ip, ban);
+        })
+    }
+
+    /// Unban lifts the ban of ip, returning false if it was not banned
+    pub fn unban(&self, ip: IpAddr) -> Result<bool> {
+        self.update(true, |bans| bans.remove(&ip).is_some())
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.update(true, |bans| bans.clear())
+    }
+
+    /// List returns the bans that have not expired, sorted by address
+    pub fn list(&self) -> Result<Vec<Ban>> {
+        let mut bans = self.update(true, |bans| bans.values().cloned().collect::<Vec<Ban>>())?;
+        bans.sort_by_key(|ban| ban.ip);
+        Ok(bans)
+    }
+
+    /// Update applies f to the bans, written back to the db if f changed them
+    ///
+    /// The bans are read from the db first if reload is set or they were never read.
+    /// Expired bans are dropped before f sees them.
+    fn update<T>(&self, reload: bool, f: impl FnOnce(&mut HashMap<IpAddr, Ban>) -> T) -> Result<T> {
+        let mut cache = self.cache.lock().unwrap();
+        let mut db = None;
+        if let Some(path) = &self.path {
+            if reload || cache.is_none() {
+                let opened = open(path)?;
+                let mut bans = HashMap::new();
+                for kv in opened.iter() {
+                    let (_, v) = kv?;
+                    let ban: Ban = deserialize(&v)?;
+                    bans.insert(ban.ip, ban);
+                }
+                *cache = Some(bans);
+                db = Some(opened);
+            }
+        }
+
+        let bans = cache.get_or_insert_with(HashMap::new);
+        let now = now();
+        bans.retain(|_, ban| ban.until > now);
+        let unchanged = bans.clone();
+        let result = f(bans);
+
+        if let Some(db) = db {
+            if *bans != unchanged {
+                db.clear()?;
+                for ban in bans.values() {
+                    db.insert(ban.ip.to_string(), serialize(ban)?)?;
+                }
+                db.flush()?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Open opens the db at path, waiting while another process has it open
+fn open(path: &Path) -> Result<sled::Db> {
+    let mut attempts = 1;
+    loop {
+        match sled::open(path) {
+            Ok(db) => return Ok(db),
+            Err(_) if attempts < OPEN_ATTEMPTS => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::temp_path;
+
+    #[test]
+    fn test_banlist() {
+        let path = temp_path("banlist");
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let bans = BanList::new(Some(path.clone()));
+        bans.ban(ip, Duration::from_secs(60), "invalid block")
+            .unwrap();
+        bans.ban(other, Duration::ZERO, "expired").unwrap();
+        assert!(bans.is_banned(ip).unwrap());
+        assert!(!bans.is_banned(other).unwrap());
+
+        // bans outlive the node that set them
+        let reopened = BanList::new(Some(path.clone()));
+        let list = reopened.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!((list[0].ip, list[0].reason.as_str()), (ip, "invalid block"));
+        assert!(reopened.unban(ip).unwrap());
+        assert!(!reopened.unban(ip).unwrap());
+        // the other list sees it once it reads the db again
+        assert!(bans.is_banned(ip).unwrap());
+        assert!(bans.list().unwrap().is_empty());
+        assert!(!bans.is_banned(ip).unwrap());
+
+        let memory = BanList::new(None);
+        memory.ban(ip, Duration::from_secs(60), "").unwrap();
+        memory.ban(other, Duration::from_secs(60), "").unwrap();
+        assert_eq!(memory.list().unwrap().len(), 2);
+        memory.clear().unwrap();
+        assert!(memory.list().unwrap().is_empty());
+        drop((bans, reopened));
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
@@ -1,16 +1,26 @@
-use super::*;
 use crate::errors::Result;
+use crate::miner::Miner;
+use crate::pow;
 use crate::transaction::Transaction;
-use bincode::{deserialize, serialize};
-use colored::*;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use log::{debug, info};
-use merkle_cbt::merkle_tree::{Merge, CBMT};
+use failure::format_err;
+use merkle_cbt::merkle_tree::{Merge, MerkleProof, CBMT};
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
-pub const TARGET_HEX: usize = 4;
+/// INITIAL_BITS is the difficulty of the genesis block, in compact form
+pub const INITIAL_BITS: u32 = 0x1f00ffff;
+/// RETARGET_INTERVAL is the number of blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: i32 = 10;
+/// TARGET_BLOCK_TIME is the expected time between two blocks in milliseconds
+pub const TARGET_BLOCK_TIME: u128 = 10_000;
+/// BLOCK_VERSION is the version of the blocks this node mines
+pub const BLOCK_VERSION: i32 = 1;
+/// MEDIAN_TIME_SPAN is the number of blocks whose median timestamp a new block must pass
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// MAX_FUTURE_BLOCK_TIME is how far in milliseconds a timestamp may be ahead of local time
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
 
 struct MergeTX {}
 
@@ -28,13 +38,74 @@ impl Merge for MergeTX {
     }
 }
 
+/// BlockHeader is the part of a block its hash and proof of work are computed over
+///
+/// It commits to the transactions through `merkle_root`, so headers can be synced and
+/// checked without the block bodies. `bits` is the difficulty of the block: the compact
+/// form of the target its hash must meet.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub timestamp: u128,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// Hash returns the hash of the header in hex, which is the hash of its block
+    pub fn hash(&self) -> Result<String> {
+        let data = bincode::serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        Ok(hasher.result_str())
+    }
+
+    /// CheckProofOfWork checks the header hash meets its difficulty
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        let data = bincode::serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        let mut hash: [u8; 32] = [0; 32];
+        hasher.result(&mut hash);
+
+        Ok(pow::check_proof_of_work(&hash, self.bits))
+    }
+}
+
+/// TxProof shows a transaction is committed to by the merkle root of a block
+///
+/// The proof carries the whole transaction: the merkle tree is built over the hashes
+/// of signed transactions, and its id can only be checked with the transaction at hand.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TxProof {
+    pub block_hash: String,
+    pub transaction: Transaction,
+    /// position of the transaction's leaf in the merkle tree
+    pub index: u32,
+    /// sibling hashes on the path from the leaf to the root
+    pub lemmas: Vec<Vec<u8>>,
+}
+
+impl TxProof {
+    /// Verify checks the proof against the header of the block it names
+    pub fn verify(&self, header: &BlockHeader) -> Result<bool> {
+        if header.hash()? != self.block_hash || !self.transaction.check_id()? {
+            return Ok(false);
+        }
+        let leaf = self.transaction.hash()?.as_bytes().to_owned();
+        let proof = MerkleProof::<Vec<u8>, MergeTX>::new(vec![self.index], self.lemmas.clone());
+        Ok(proof.verify(&header.merkle_root, &[leaf]))
+    }
+}
+
+/// Block keeps block headers and its transactions
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Block {
-    nonce: u32,
+    header: BlockHeader,
     height: i32,
-    timestamp: u128,
     hash: String,
-    prev_block_hash: String,
     transactions: Vec<Transaction>,
 }
 
@@ -43,86 +114,207 @@ impl Block {
         data: Vec<Transaction>,
         prev_block_hash: String,
         height: i32,
+        bits: u32,
     ) -> Result<Block> {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_millis();
+        Block::new_template(data, prev_block_hash, height, bits)?.mine()
+    }
 
-        let mut block = Block {
-            timestamp,
+    /// NewTemplate returns a block that still has to be mined
+    pub fn new_template(
+        data: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        bits: u32,
+    ) -> Result<Block> {
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            prev_block_hash,
+            merkle_root: merkle_root(&data)?,
+            timestamp: now()?,
+            bits,
             nonce: 0,
+        };
+        Ok(Block {
+            header,
             height,
-            prev_block_hash,
             hash: String::new(),
             transactions: data,
-        };
+        })
+    }
 
-        block.proof_of_work()?;
-        Ok(block)
+    /// FromParts puts a stored header and body back together
+    pub fn from_parts(
+        hash: String,
+        header: BlockHeader,
+        height: i32,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        Block {
+            header,
+            height,
+            hash,
+            transactions,
+        }
+    }
+
+    /// Mine mines the block on every core, returning it with its nonce and hash set
+    pub fn mine(self) -> Result<Block> {
+        let miner = Miner::default();
+        let job = miner.job();
+        miner
+            .mine(self, job)?
+            .ok_or_else(|| format_err!("ERROR: Mining was cancelled"))
     }
 
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_height(&self) -> i32 {
         self.height
     }
 
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            transactions.push(tx.clone().hash()?.as_bytes().to_owned());
-        }
-        let merkle_tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
-        Ok(merkle_tree.root())
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
     }
 
     pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.prev_block_hash.clone()
     }
 
-    fn proof_of_work(&mut self) -> Result<()> {
-        info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
-        }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.header.nonce = nonce;
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEX,
-            self.nonce,
-        );
+    pub fn set_timestamp(&mut self, timestamp: u128) {
+        self.header.timestamp = timestamp;
+    }
 
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes)
+    /// RollTimestamp moves the timestamp forward to give the miner fresh nonces
+    pub fn roll_timestamp(&mut self) -> Result<()> {
+        self.header.timestamp = now()?.max(self.header.timestamp + 1);
+        Ok(())
+    }
+
+    /// Seal sets the block hash once a nonce is found
+    pub fn seal(&mut self) -> Result<()> {
+        self.hash = self.header.hash()?;
+        Ok(())
     }
 
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+        Block::new_block(vec![coinbase], String::new(), 0, INITIAL_BITS).unwrap()
     }
 
     pub fn get_transaction(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1 = vec![];
-        vec1.resize(TARGET_HEX, '0' as u8);
-        println!("{}", format!("{:#?}", vec1).green());
+    /// GetTxProof returns a merkle proof that the transaction txid is in the block
+    pub fn get_tx_proof(&self, txid: &str) -> Result<Option<TxProof>> {
+        let position = match self.transactions.iter().position(|tx| tx.id == txid) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let proof = CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(
+            &leaves(&self.transactions)?,
+            &[position as u32],
+        )
+        .ok_or_else(|| format_err!("ERROR: Cannot build merkle proof"))?;
+        Ok(Some(TxProof {
+            block_hash: self.hash.clone(),
+            transaction: self.transactions[position].clone(),
+            index: proof.indices()[0],
+            lemmas: proof.lemmas().to_vec(),
+        }))
+    }
+
+    /// CheckMerkleRoot checks the merkle root commits to the block's transactions
+    pub fn check_merkle_root(&self) -> Result<bool> {
+        Ok(merkle_root(&self.transactions)? == self.header.merkle_root)
+    }
+
+    /// CheckHash checks the stored hash is the hash of the block header
+    pub fn check_hash(&self) -> Result<bool> {
+        Ok(self.header.hash()? == self.hash)
+    }
+
+    /// CheckProofOfWork checks the block hash meets its difficulty
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        self.header.check_proof_of_work()
+    }
+}
+
+fn leaves(transactions: &[Transaction]) -> Result<Vec<Vec<u8>>> {
+    let mut hashes = Vec::new();
+    for tx in transactions {
+        hashes.push(tx.hash()?.as_bytes().to_owned());
+    }
+    Ok(hashes)
+}
+
+fn merkle_root(transactions: &[Transaction]) -> Result<Vec<u8>> {
+    let merkle_tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&leaves(transactions)?);
+    Ok(merkle_tree.root())
+}
+
+/// Now returns the local time in milliseconds since the Unix epoch
+pub fn now() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_header() {
+        let address = Wallet::new().get_address();
+        let cbtx = Transaction::new_coinbase(address, String::new(), 0, Amount::ZERO).unwrap();
+        let block = Block::new_block(vec![cbtx], String::new(), 0, pow::POW_LIMIT_BITS).unwrap();
+        assert_eq!(block.get_header().hash().unwrap(), block.get_hash());
+        assert!(block.check_merkle_root().unwrap());
+
+        let mut header = block.get_header().clone();
+        header.nonce = header.nonce.wrapping_add(1);
+        assert_ne!(header.hash().unwrap(), block.get_hash());
+    }
+
+    #[test]
+    fn test_tx_proof() {
+        let address = Wallet::new().get_address();
+        let txs: Vec<Transaction> = (0..5)
+            .map(|i| Transaction::new_coinbase(address.clone(), i.to_string(), 1, Amount::ZERO))
+            .collect::<Result<_>>()
+            .unwrap();
+        let mut block = Block::new_template(txs.clone(), String::new(), 1, INITIAL_BITS).unwrap();
+        block.seal().unwrap();
+
+        for tx in &txs {
+            let proof = block.get_tx_proof(&tx.id).unwrap().unwrap();
+            assert!(proof.verify(block.get_header()).unwrap());
+        }
+        assert!(block.get_tx_proof("missing").unwrap().is_none());
+
+        let mut proof = block.get_tx_proof(&txs[3].id).unwrap().unwrap();
+        proof.transaction = txs[2].clone();
+        assert!(!proof.verify(block.get_header()).unwrap());
 
-        Ok(&hasher.result_str()[0..TARGET_HEX] == String::from_utf8(vec1)?)
+        let mut header = block.get_header().clone();
+        header.nonce += 1;
+        let proof = block.get_tx_proof(&txs[3].id).unwrap().unwrap();
+        assert!(!proof.verify(&header).unwrap());
     }
 }
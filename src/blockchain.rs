@@ -1,22 +1,60 @@
 //! Blockchain
 
 use super::*;
+use crate::amount::Amount;
 use crate::block::*;
+use crate::errors::BlockError;
+use crate::pow::{block_work, retarget};
+use crate::store::{Batch, ChainStore, SledStore, Store, UtxoStore};
 use crate::transaction::*;
-use bincode::{deserialize, serialize};
+use crate::utxoset::UTXOSet;
 use failure::format_err;
 use log::{debug, info};
-use sled;
-use std::collections::HashMap;
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
 /// Blockchain implements interactions with a DB
-#[derive(Debug)]
 pub struct Blockchain {
     pub tip: String,
-    pub db: sled::Db,
+    store: Arc<dyn Store>,
+    txindex: bool,
+}
+
+/// BlockIndex keeps what is known about a stored header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockIndex {
+    height: i32,
+    /// total work of the chain ending at this block, big-endian
+    chain_work: [u8; 32],
+    invalid: bool,
+    /// whether the block's transactions are stored too
+    has_body: bool,
+}
+
+/// TxLocation is where the transaction index finds a transaction of the active chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub block_hash: String,
+    /// position of the transaction in the block
+    pub position: u32,
+}
+
+/// ChainUpdate describes how the active chain moved
+///
+/// `disconnected` runs from the old tip down to the fork point and `connected`
+/// from the fork point up to the new tip.
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
+    /// why a block was found invalid while connecting, if one was
+    pub invalid: Option<BlockError>,
 }
 
 /// BlockchainIterator is used to iterate over blockchain blocks
@@ -25,136 +63,245 @@ pub struct BlockchainIterator<'a> {
     bc: &'a Blockchain,
 }
 
+impl ChainUpdate {
+    /// Append adds the changes of a later update
+    ///
+    /// Blocks connected by one update and disconnected by the other cancel out.
+    pub fn append(&mut self, mut later: ChainUpdate) {
+        cancel_blocks(&mut self.connected, &mut later.disconnected);
+        cancel_blocks(&mut self.disconnected, &mut later.connected);
+        self.disconnected.append(&mut later.disconnected);
+        self.connected.append(&mut later.connected);
+        self.invalid = self.invalid.take().or(later.invalid);
+    }
+}
+
+fn cancel_blocks(a: &mut Vec<Block>, b: &mut Vec<Block>) {
+    let in_a: HashSet<String> = a.iter().map(|blk| blk.get_hash()).collect();
+    let common: HashSet<String> = b
+        .iter()
+        .map(|blk| blk.get_hash())
+        .filter(|h| in_a.contains(h))
+        .collect();
+    a.retain(|blk| !common.contains(&blk.get_hash()));
+    b.retain(|blk| !common.contains(&blk.get_hash()));
+}
+
 impl Blockchain {
-    /// NewBlockchain creates a new Blockchain db
-    pub fn new() -> Result<Blockchain> {
+    /// NewBlockchain opens the Blockchain db in datadir
+    pub fn new(datadir: &Path) -> Result<Blockchain> {
+        Blockchain::open(datadir, "blocks")
+    }
+
+    /// Open opens the Blockchain db called name in datadir, creating an empty one if there is none
+    pub fn open(datadir: &Path, name: &str) -> Result<Blockchain> {
         info!("open blockchain");
+        Blockchain::from_store(Box::new(SledStore::open(&datadir.join(name))?))
+    }
 
-        let db = sled::open("data/blocks")?;
-        let hash = match db.get("LAST")? {
-            Some(l) => l.to_vec(),
-            None => Vec::new(),
-        };
+    /// FromStore opens the Blockchain kept in a store
+    pub fn from_store(store: Box<dyn Store>) -> Result<Blockchain> {
+        let tip = store.get_tip()?;
+        let txindex = store.has_txindex()?;
         info!("Found block database");
-        let lasthash = if hash.is_empty() {
-            String::new()
-        } else {
-            String::from_utf8(hash.to_vec())?
+        let bc = Blockchain {
+            tip,
+            store: Arc::from(store),
+            txindex,
         };
-        Ok(Blockchain { tip: lasthash, db })
+        if !bc.tip.is_empty() && bc.get_block_hash(bc.get_best_height()?)? != Some(bc.tip.clone()) {
+            info!("rebuild the height index");
+            let batch = bc.batch();
+            bc.move_tip(&batch, &bc.tip)?;
+            batch.commit()?;
+        }
+        Ok(bc)
     }
 
     /// CreateBlockchain creates a new blockchain DB
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    pub fn create_blockchain(address: String, datadir: &Path) -> Result<Blockchain> {
         info!("Creating new blockchain");
 
-        std::fs::remove_dir_all("data/blocks").ok();
-        let db = sled::open("data/blocks")?;
+        std::fs::remove_dir_all(datadir.join("blocks")).ok();
+        let store = SledStore::open(&datadir.join("blocks"))?;
         debug!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        Blockchain::create_with_store(address, Box::new(store))
+    }
+
+    /// CreateWithStore creates a new blockchain in an empty store
+    pub fn create_with_store(address: String, store: Box<dyn Store>) -> Result<Blockchain> {
+        let cbtx = Transaction::new_coinbase(
+            address,
+            String::from(GENESIS_COINBASE_DATA),
+            0,
+            Amount::ZERO,
+        )?;
         let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
-            tip: genesis.get_hash(),
-            db,
-        };
-        bc.db.flush()?;
+        let mut bc = Blockchain::from_store(store)?;
+        let batch = bc.batch();
+        bc.put_block(&batch, &genesis)?;
+        bc.move_tip(&batch, &genesis.get_hash())?;
+        bc.commit(batch)?;
         Ok(bc)
     }
 
-    /// MineBlock mines a new block with the provided transactions
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        info!("mine a new block");
+    /// Batch starts a set of writes to commit at once
+    pub fn batch(&self) -> Batch {
+        Batch::new(self.store.clone())
+    }
+
+    /// Commit writes a batch, then picks up the tip it may have moved
+    pub fn commit(&mut self, batch: Batch) -> Result<()> {
+        batch.commit()?;
+        self.tip = self.store.get_tip()?;
+        Ok(())
+    }
 
+    /// NewBlockTemplate returns an unmined block with the provided transactions on top of the tip
+    pub fn new_block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
         for tx in &transactions {
             if !self.verify_transacton(tx)? {
                 return Err(format_err!("ERROR: Invalid transaction"));
             }
         }
 
-        let lasthash = self.db.get("LAST")?.unwrap();
-
-        let newblock = Block::new_block(
-            transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
-
-        self.tip = newblock.get_hash();
-        Ok(newblock)
+        let lasthash = self.tip.clone();
+        let bits = self.get_next_bits(&lasthash)?;
+        let min_time = self.get_median_time_past(&lasthash)? + 1;
+        let mut block =
+            Block::new_template(transactions, lasthash, self.get_best_height()? + 1, bits)?;
+        block.set_timestamp(block.get_timestamp().max(min_time));
+        Ok(block)
     }
 
     /// Iterator returns a BlockchainIterat
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.tip.clone(),
-            bc: &self,
+            bc: self,
         }
     }
 
-    /// FindUTXO finds and returns all unspent transaction outputs
-    pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
-
-        for block in self.iter() {
-            for tx in block.get_transaction() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
+    /// FindTransaction finds a transaction by its ID
+    pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
+        match self.find_transaction_block(id)? {
+            Some((block, position)) => Ok(block.get_transaction()[position].clone()),
+            None => Err(format_err!("Transaction is not found")),
+        }
+    }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }
-                }
+    /// FindTransactionBlock returns the block of the active chain holding a transaction,
+    /// along with the position of the transaction in it
+    ///
+    /// The transaction index is used if it is kept, otherwise blocks are scanned from the tip.
+    pub fn find_transaction_block(&self, id: &str) -> Result<Option<(Block, usize)>> {
+        if self.txindex {
+            return match self.store.get_tx_location(id)? {
+                Some(location) => Ok(Some((
+                    self.get_block(&location.block_hash)?,
+                    location.position as usize,
+                ))),
+                None => Ok(None),
+            };
+        }
+        for b in self.iter() {
+            if let Some(position) = b.get_transaction().iter().position(|tx| tx.id == id) {
+                return Ok(Some((b, position)));
+            }
+        }
+        Ok(None)
+    }
 
-                if !tx.is_coinbase() {
-                    for i in &tx.vin {
-                        match spend_txos.get_mut(&i.txid) {
-                            Some(v) => {
-                                v.push(i.vout);
-                            }
-                            None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                            }
-                        }
-                    }
-                }
+    /// GetTxProof returns a merkle proof for a transaction of the active chain
+    pub fn get_tx_proof(&self, txid: &str) -> Result<TxProof> {
+        if let Some((block, _)) = self.find_transaction_block(txid)? {
+            if let Some(proof) = block.get_tx_proof(txid)? {
+                return Ok(proof);
             }
         }
+        Err(format_err!("Transaction is not found"))
+    }
 
-        utxos
+    /// EnableTxIndex starts keeping the transaction index, building it for the active chain
+    ///
+    /// Once enabled, the index is kept up to date as blocks are connected and disconnected.
+    pub fn enable_txindex(&mut self) -> Result<()> {
+        if self.txindex {
+            return Ok(());
+        }
+        info!("build the transaction index");
+        let batch = self.batch();
+        for height in 0..=self.get_best_height()? {
+            self.write_tx_locations(&batch, &self.get_block_by_height(height)?, true)?;
+        }
+        batch.put_txindex()?;
+        batch.commit()?;
+        self.txindex = true;
+        Ok(())
     }
 
-    /// FindTransaction finds a transaction by its ID
-    pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
+    /// IndexTransactions adds the transactions of a block to the transaction index in batch,
+    /// or removes them when the block is disconnected
+    ///
+    /// It does nothing unless the index is kept.
+    pub fn index_transactions(&self, batch: &Batch, block: &Block, connect: bool) -> Result<()> {
+        if !self.txindex {
+            return Ok(());
+        }
+        self.write_tx_locations(batch, block, connect)
+    }
+
+    fn write_tx_locations(&self, batch: &Batch, block: &Block, connect: bool) -> Result<()> {
+        for (position, tx) in block.get_transaction().iter().enumerate() {
+            if connect {
+                let location = TxLocation {
+                    block_hash: block.get_hash(),
+                    position: position as u32,
+                };
+                batch.put_tx_location(&tx.id, &location)?;
+            } else {
+                batch.remove_tx_location(&tx.id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// GetAddressProofs returns merkle proofs for the transactions of the active chain
+    /// that pay to or spend from any of the public key hashes, newest block first
+    ///
+    /// With the address index only the blocks holding those transactions are read;
+    /// without it the whole chain is scanned.
+    pub fn get_address_proofs(&self, pub_key_hashes: &[Vec<u8>]) -> Result<Vec<TxProof>> {
+        if self.store.has_addrindex()? {
+            let mut txids: BTreeMap<i32, BTreeSet<String>> = BTreeMap::new();
+            for pub_key_hash in pub_key_hashes {
+                for event in self.store.get_address_events(pub_key_hash)? {
+                    let txid = event.spent_by.unwrap_or(event.txid);
+                    txids.entry(event.height).or_default().insert(txid);
+                }
+            }
+            let mut proofs = Vec::new();
+            for (height, txids) in txids.into_iter().rev() {
+                let block = self.get_block_by_height(height)?;
+                for txid in txids {
+                    proofs.extend(block.get_tx_proof(&txid)?);
+                }
+            }
+            return Ok(proofs);
+        }
+
+        let mut proofs = Vec::new();
         for b in self.iter() {
             for tx in b.get_transaction() {
-                if tx.id == id {
-                    return Ok(tx.clone());
+                if pub_key_hashes.iter().any(|pkh| tx.touches(pkh)) {
+                    proofs.extend(b.get_tx_proof(&tx.id)?);
                 }
             }
         }
-        Err(format_err!("Transaction is not found"))
+        Ok(proofs)
     }
 
+    #[allow(non_snake_case)]
     fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_TXs = HashMap::new();
         for vin in &tx.vin {
@@ -164,7 +311,18 @@ impl Blockchain {
         Ok(prev_TXs)
     }
 
+    /// GetTransactionFee returns the fee a transaction pays to the miner
+    #[allow(non_snake_case)]
+    pub fn get_transaction_fee(&self, tx: &Transaction) -> Result<Amount> {
+        if tx.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+        let prev_TXs = self.get_prev_TXs(tx)?;
+        tx.fee(&prev_TXs)
+    }
+
     /// SignTransaction signs inputs of a Transaction
+    #[allow(non_snake_case)]
     pub fn sign_transacton(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
         let prev_TXs = self.get_prev_TXs(tx)?;
         tx.sign(private_key, prev_TXs)?;
@@ -172,6 +330,7 @@ impl Blockchain {
     }
 
     /// VerifyTransaction verifies transaction input signatures
+    #[allow(non_snake_case)]
     pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
         if tx.is_coinbase() {
             return Ok(true);
@@ -180,47 +339,506 @@ impl Blockchain {
         tx.verify(prev_TXs)
     }
 
+    /// AddHeader saves a header whose block body is not known yet
+    ///
+    /// It returns false if the header is already stored.
+    pub fn add_header(&mut self, header: &BlockHeader) -> Result<bool> {
+        let hash = header.hash()?;
+        if self.get_block_index(&hash)?.is_some() {
+            return Ok(false);
+        }
+        let height = self.check_header(header)?;
+        let batch = self.batch();
+        self.put_header(&batch, &hash, header, height, false)?;
+        self.commit(batch)?;
+        Ok(true)
+    }
+
+    /// CheckHeader runs the consensus checks on a header and returns its height
+    ///
+    /// The parent header has to be stored already, so headers are accepted parents first.
+    /// The timestamp must be past the median of the last MEDIAN_TIME_SPAN blocks and at
+    /// most MAX_FUTURE_BLOCK_TIME ahead of local time, so miners cannot warp the clock
+    /// the difficulty is computed from.
+    pub fn check_header(&self, header: &BlockHeader) -> Result<i32> {
+        if header.version < 1 {
+            return Err(BlockError::BadVersion.into());
+        }
+        if !header.check_proof_of_work()? {
+            return Err(BlockError::BadProofOfWork.into());
+        }
+        let height = if header.prev_block_hash.is_empty() {
+            0
+        } else {
+            let parent = match self.get_block_index(&header.prev_block_hash)? {
+                Some(index) => index,
+                None => return Err(BlockError::UnknownParent.into()),
+            };
+            if parent.invalid {
+                return Err(BlockError::InvalidParent.into());
+            }
+            parent.height + 1
+        };
+        if header.bits != self.get_next_bits(&header.prev_block_hash)? {
+            return Err(BlockError::BadBits.into());
+        }
+        if height > 0 && header.timestamp <= self.get_median_time_past(&header.prev_block_hash)? {
+            return Err(BlockError::TimeTooOld.into());
+        }
+        if header.timestamp > now()? + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::TimeTooNew.into());
+        }
+        Ok(height)
+    }
+
     /// AddBlock saves the block into the blockchain
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
+    ///
+    /// The active chain is left untouched; it returns true when the new block ends a
+    /// chain with more work than the current tip, so the caller should reorganize to it.
+    pub fn add_block(&mut self, block: Block) -> Result<bool> {
+        if self.has_block(&block.get_hash())? {
+            return Ok(false);
         }
-        self.db.insert(block.get_hash(), data)?;
+        self.check_block(&block)?;
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
-            self.db.flush()?;
+        let batch = self.batch();
+        let work = self.put_block(&batch, &block)?;
+        self.commit(batch)?;
+        if self.tip.is_empty() {
+            return Ok(true);
+        }
+        Ok(work > self.get_chain_work(&self.tip.clone())?)
+    }
+
+    /// CheckBlock runs the consensus checks that do not depend on the UTXO set
+    ///
+    /// The parent block, body included, has to be stored already, so blocks are
+    /// accepted parents first.
+    pub fn check_block(&self, block: &Block) -> Result<()> {
+        if !block.check_hash()? {
+            return Err(BlockError::BadHash.into());
+        }
+        if block.get_height() != self.check_header(block.get_header())? {
+            return Err(BlockError::BadHeight.into());
+        }
+        if !block.get_prev_hash().is_empty() {
+            match self.get_block_index(&block.get_prev_hash())? {
+                Some(parent) if parent.has_body => {}
+                _ => return Err(BlockError::UnknownParent.into()),
+            }
+        }
+        if !block.check_merkle_root()? {
+            return Err(BlockError::BadMerkleRoot.into());
+        }
+
+        let txs = block.get_transaction();
+        if txs.is_empty() || !txs[0].is_coinbase() || txs[1..].iter().any(|tx| tx.is_coinbase()) {
+            return Err(BlockError::BadCoinbase.into());
+        }
+        if txs[0].coinbase_height() != Some(block.get_height()) {
+            return Err(BlockError::BadCoinbaseHeight.into());
+        }
+        for tx in txs {
+            if !tx.check_id()? {
+                return Err(BlockError::BadTxid(tx.id.clone()).into());
+            }
         }
         Ok(())
     }
 
+    /// ValidateBlock fully validates a block about to be connected on top of the tip
+    ///
+    /// The block has to have passed CheckBlock already. On top of it, every input must
+    /// spend an output of the UTXO set with a valid signature, no output may be spent
+    /// twice within the block, and the coinbase may claim no more than the subsidy plus
+    /// the fees of the other transactions.
+    pub fn validate_block(&self, block: &Block, utxo: &UTXOSet) -> Result<()> {
+        if block.get_prev_hash() != self.tip {
+            return Err(BlockError::NotOnTip.into());
+        }
+
+        let txs = block.get_transaction();
+        let mut spent = HashSet::new();
+        let mut fees = Amount::ZERO;
+        for tx in &txs[1..] {
+            let mut values = Vec::new();
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid.clone(), vin.vout)) {
+                    return Err(BlockError::DoubleSpend(tx.id.clone()).into());
+                }
+                match utxo.find_output(&vin.txid, vin.vout)? {
+                    Some(utxo) if !utxo.is_mature_at(block.get_height()) => {
+                        return Err(BlockError::ImmatureSpend(tx.id.clone()).into())
+                    }
+                    Some(utxo) => values.push(utxo.output.value),
+                    None => return Err(BlockError::MissingInput(tx.id.clone()).into()),
+                }
+            }
+            let fee = Amount::checked_sum(values)
+                .zip(tx.value_out().ok())
+                .and_then(|(value_in, value_out)| value_in.checked_sub(value_out));
+            fees = match fee.and_then(|fee| fees.checked_add(fee)) {
+                Some(fees) => fees,
+                None => return Err(BlockError::BadValue(tx.id.clone()).into()),
+            };
+            if !matches!(self.verify_transacton(tx), Ok(true)) {
+                return Err(BlockError::BadSignature(tx.id.clone()).into());
+            }
+        }
+
+        let subsidy = block_subsidy(block.get_height());
+        match (txs[0].value_out().ok(), subsidy.checked_add(fees)) {
+            (Some(reward), Some(max_reward)) if reward <= max_reward => Ok(()),
+            _ => Err(BlockError::BadCoinbaseValue.into()),
+        }
+    }
+
+    /// PutBlock stores a block, header and body, returning the chain work up to it
+    pub fn put_block(&self, batch: &Batch, block: &Block) -> Result<U256> {
+        batch.put_body(&block.get_hash(), block.get_transaction())?;
+        self.put_header(
+            batch,
+            &block.get_hash(),
+            block.get_header(),
+            block.get_height(),
+            true,
+        )
+    }
+
+    /// PutHeader stores a header and its index entry, returning the chain work up to it
+    fn put_header(
+        &self,
+        batch: &Batch,
+        hash: &str,
+        header: &BlockHeader,
+        height: i32,
+        has_body: bool,
+    ) -> Result<U256> {
+        let parent_work = if header.prev_block_hash.is_empty() {
+            U256::zero()
+        } else {
+            self.get_chain_work(&header.prev_block_hash)?
+        };
+        let work = parent_work + block_work(header.bits);
+        let mut chain_work = [0; 32];
+        work.to_big_endian(&mut chain_work);
+        let index = BlockIndex {
+            height,
+            chain_work,
+            invalid: false,
+            has_body,
+        };
+        batch.put_header(hash, header)?;
+        batch.put_block_index(hash, &index)?;
+        Ok(work)
+    }
+
+    fn get_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>> {
+        self.store.get_block_index(block_hash)
+    }
+
+    /// GetChainWork returns the total work of the chain ending at the block
+    pub fn get_chain_work(&self, block_hash: &str) -> Result<U256> {
+        match self.get_block_index(block_hash)? {
+            Some(index) => Ok(U256::from_big_endian(&index.chain_work)),
+            None => Err(format_err!("Block is not found")),
+        }
+    }
+
+    /// InvalidateBlock marks a block as invalid so no chain containing it becomes active
+    pub fn invalidate_block(&mut self, block_hash: &str) -> Result<()> {
+        if let Some(mut index) = self.get_block_index(block_hash)? {
+            index.invalid = true;
+            self.store.put_block_index(block_hash, &index)?;
+        }
+        Ok(())
+    }
+
+    /// SetTip moves the active chain to end at the block
+    pub fn set_tip(&mut self, block_hash: &str) -> Result<()> {
+        let batch = self.batch();
+        self.move_tip(&batch, block_hash)?;
+        self.commit(batch)
+    }
+
+    /// MoveTip writes the new tip to batch, along with the height index of the new active chain
+    ///
+    /// Heights above the new tip are dropped, and the ones below it rewritten back to
+    /// the point where the old and new chains meet.
+    pub fn move_tip(&self, batch: &Batch, block_hash: &str) -> Result<()> {
+        let height_of = |hash: &str| -> Result<i32> {
+            match batch.get_block_index(hash)? {
+                Some(index) => Ok(index.height),
+                None if hash.is_empty() => Ok(-1),
+                None => Err(format_err!("Block is not found")),
+            }
+        };
+        let old_height = height_of(&batch.get_tip()?)?;
+        let mut height = height_of(block_hash)?;
+        for h in height + 1..=old_height {
+            batch.remove_hash_by_height(h)?;
+        }
+
+        let mut hash = block_hash.to_string();
+        while height >= 0 && batch.get_hash_by_height(height)?.as_ref() != Some(&hash) {
+            batch.put_hash_by_height(height, &hash)?;
+            hash = match batch.get_header(&hash)? {
+                Some(header) => header.prev_block_hash,
+                None => return Err(format_err!("Block is not found")),
+            };
+            height -= 1;
+        }
+        batch.put_tip(block_hash)
+    }
+
+    /// FindFork returns the blocks to disconnect and to connect to move from old_tip to new_tip
+    pub fn find_fork(&self, old_tip: &str, new_tip: &str) -> Result<ChainUpdate> {
+        let mut update = ChainUpdate::default();
+        let mut new = self.lookup_block(new_tip)?;
+        let mut old = self.lookup_block(old_tip)?;
+
+        loop {
+            let (new_height, old_height) = match (&new, &old) {
+                (Some(n), Some(o)) if n.get_hash() == o.get_hash() => break,
+                (None, None) => break,
+                (n, o) => (
+                    n.as_ref().map_or(-1, |b| b.get_height()),
+                    o.as_ref().map_or(-1, |b| b.get_height()),
+                ),
+            };
+            if new_height >= old_height {
+                let block = new.take().unwrap();
+                new = self.get_parent(&block)?;
+                update.connected.push(block);
+            }
+            if old_height >= new_height {
+                let block = old.take().unwrap();
+                old = self.get_parent(&block)?;
+                update.disconnected.push(block);
+            }
+        }
+
+        update.connected.reverse();
+        Ok(update)
+    }
+
+    fn get_parent(&self, block: &Block) -> Result<Option<Block>> {
+        self.lookup_block(&block.get_prev_hash())
+    }
+
+    fn lookup_block(&self, block_hash: &str) -> Result<Option<Block>> {
+        if block_hash.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.get_block(block_hash)?))
+    }
+
+    /// HasBlock checks whether the body of a block is stored
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(matches!(self.get_block_index(block_hash)?, Some(index) if index.has_body))
+    }
+
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
-        Ok(block)
+        let index = self.get_block_index(block_hash)?;
+        let (index, body) = match (index, self.store.get_body(block_hash)?) {
+            (Some(index), Some(body)) if index.has_body => (index, body),
+            _ => return Err(format_err!("Block is not found")),
+        };
+        Ok(Block::from_parts(
+            block_hash.to_string(),
+            self.get_header(block_hash)?,
+            index.height,
+            body,
+        ))
+    }
+
+    /// GetHeader finds a block header by its hash and returns it
+    pub fn get_header(&self, block_hash: &str) -> Result<BlockHeader> {
+        match self.store.get_header(block_hash)? {
+            Some(header) => Ok(header),
+            None => Err(format_err!("Block is not found")),
+        }
+    }
+
+    /// GetLocator returns hashes of the active chain for a peer to find the fork point
+    ///
+    /// The hashes are read from the height index at the heights of locator_heights.
+    pub fn get_locator(&self) -> Result<Vec<String>> {
+        let mut locator = Vec::new();
+        for height in locator_heights(self.get_best_height()?) {
+            if let Some(hash) = self.get_block_hash(height)? {
+                locator.push(hash);
+            }
+        }
+        Ok(locator)
+    }
+
+    /// GetHeadersAfter returns up to max headers of the active chain following the fork point
+    ///
+    /// The fork point is the first locator hash on the active chain. If there is none,
+    /// headers are returned from the genesis block on.
+    pub fn get_headers_after(&self, locator: &[String], max: usize) -> Result<Vec<BlockHeader>> {
+        let mut start = 0;
+        for hash in locator {
+            if let Some(height) = self.get_active_height(hash)? {
+                start = height + 1;
+                break;
+            }
+        }
+        let end = start.saturating_add(max.min(i32::MAX as usize) as i32);
+
+        let mut headers = Vec::new();
+        for hash in self.get_block_hashes_by_height(start, end)? {
+            headers.push(self.get_header(&hash)?);
+        }
+        Ok(headers)
+    }
+
+    /// GetMissingBodies returns the hashes of the blocks up to block_hash whose body is missing
+    ///
+    /// They are ordered parents first, the order in which blocks can be accepted.
+    pub fn get_missing_bodies(&self, block_hash: &str) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        let mut hash = block_hash.to_string();
+        while let Some(index) = self.get_block_index(&hash)? {
+            if index.has_body {
+                break;
+            }
+            let prev = self.get_header(&hash)?.prev_block_hash;
+            missing.push(hash);
+            hash = prev;
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    /// GetNextBits returns the difficulty required for a block on top of prev_hash
+    ///
+    /// Difficulty changes every RETARGET_INTERVAL blocks, depending on how far the
+    /// timestamps of the last window are from TARGET_BLOCK_TIME per block.
+    pub fn get_next_bits(&self, prev_hash: &str) -> Result<u32> {
+        if prev_hash.is_empty() {
+            return Ok(INITIAL_BITS);
+        }
+        let height = match self.get_block_index(prev_hash)? {
+            Some(index) => index.height,
+            None => return Err(format_err!("Block is not found")),
+        };
+        let prev = self.get_header(prev_hash)?;
+        if (height + 1) % RETARGET_INTERVAL != 0 {
+            return Ok(prev.bits);
+        }
+
+        let mut first = prev.clone();
+        for _ in 1..RETARGET_INTERVAL {
+            first = self.get_header(&first.prev_block_hash)?;
+        }
+        let actual = prev.timestamp.saturating_sub(first.timestamp);
+        let expected = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1) as u128;
+        Ok(retarget(prev.bits, actual, expected))
+    }
+
+    /// GetMedianTimePast returns the median timestamp of the last MEDIAN_TIME_SPAN blocks
+    /// ending at block_hash, or 0 for an empty chain
+    pub fn get_median_time_past(&self, block_hash: &str) -> Result<u128> {
+        let mut timestamps = Vec::new();
+        let mut hash = block_hash.to_string();
+        while !hash.is_empty() && timestamps.len() < MEDIAN_TIME_SPAN {
+            let header = self.get_header(&hash)?;
+            timestamps.push(header.timestamp);
+            hash = header.prev_block_hash;
+        }
+        if timestamps.is_empty() {
+            return Ok(0);
+        }
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    /// GetConfirmations returns how many blocks of the active chain are on top of the
+    /// block, itself included, or 0 if it is not on the active chain
+    pub fn get_confirmations(&self, block_hash: &str) -> Result<i32> {
+        match self.get_active_height(block_hash)? {
+            Some(height) => Ok(self.get_best_height()? - height + 1),
+            None => Ok(0),
+        }
+    }
+
+    /// GetActiveHeight returns the height of a block if it is on the active chain
+    fn get_active_height(&self, block_hash: &str) -> Result<Option<i32>> {
+        let height = match self.get_block_index(block_hash)? {
+            Some(index) => index.height,
+            None => return Ok(None),
+        };
+        match self.get_block_hash(height)? {
+            Some(hash) if hash == block_hash => Ok(Some(height)),
+            _ => Ok(None),
+        }
+    }
+
+    /// GetBlockHash returns the hash of the block of the active chain at height
+    pub fn get_block_hash(&self, height: i32) -> Result<Option<String>> {
+        if height < 0 {
+            return Ok(None);
+        }
+        self.store.get_hash_by_height(height)
+    }
+
+    /// GetBlockByHeight finds the block of the active chain at height and returns it
+    pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+        match self.get_block_hash(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Err(format_err!("Block is not found")),
+        }
+    }
+
+    /// GetBlockHashesByHeight returns the hashes of the active chain from height start up
+    /// to, not including, end
+    ///
+    /// The range is cut short at the tip.
+    pub fn get_block_hashes_by_height(&self, start: i32, end: i32) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        for height in start.max(0)..end {
+            match self.get_block_hash(height)? {
+                Some(hash) => hashes.push(hash),
+                None => break,
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// GetBlocksByHeight returns the blocks of the active chain from height start up to,
+    /// not including, end
+    pub fn get_blocks_by_height(&self, start: i32, end: i32) -> Result<Vec<Block>> {
+        self.get_block_hashes_by_height(start, end)?
+            .iter()
+            .map(|hash| self.get_block(hash))
+            .collect()
+    }
+
+    /// GetStore returns the store the blockchain is kept in, which holds the UTXO set too
+    pub fn get_store(&self) -> &dyn Store {
+        self.store.as_ref()
     }
 
     /// GetBestHeight returns the height of the latest block
     pub fn get_best_height(&self) -> Result<i32> {
-        let lasthash = if let Some(h) = self.db.get("LAST")? {
-            h
-        } else {
-            return Ok(-1);
-        };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
-        Ok(last_block.get_height())
+        match self.get_block_index(&self.tip)? {
+            Some(index) => Ok(index.height),
+            None => Ok(-1),
+        }
     }
 
     /// GetBlockHashes returns a list of hashes of all the blocks in the chain
+    ///
+    /// Only headers are read, so this works for a chain of headers too.
     pub fn get_block_hashs(&self) -> Vec<String> {
         let mut list = Vec::new();
-        for b in self.iter() {
-            list.push(b.get_hash());
+        let mut hash = self.tip.clone();
+        while let Ok(header) = self.get_header(&hash) {
+            list.push(hash);
+            hash = header.prev_block_hash;
         }
         list
     }
@@ -230,19 +848,224 @@ impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encoded_block) = self.bc.db.get(&self.current_hash) {
-            return match encoded_block {
-                Some(b) => {
-                    if let Ok(block) = deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            };
+        match self.bc.get_block(&self.current_hash) {
+            Ok(block) => {
+                self.current_hash = block.get_prev_hash();
+                Some(block)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// LocatorHeights returns the heights of a locator for a chain of best_height
+///
+/// The first ten heights go back from the tip one by one, then the step doubles
+/// each time. The genesis block always comes last.
+fn locator_heights(best_height: i32) -> Vec<i32> {
+    let mut heights = Vec::new();
+    let mut step = 1;
+    let mut height = best_height;
+    while height >= 0 {
+        heights.push(height);
+        if heights.len() >= 10 {
+            step *= 2;
+        }
+        height -= step;
+    }
+    if heights.last().is_some_and(|height| *height != 0) {
+        heights.push(0);
+    }
+    heights
+}
+
+/// Coinbase returns the coinbase of a test block at height paying the subsidy to address
+///
+/// data tells apart the coinbases of blocks at the same height.
+#[cfg(test)]
+pub fn coinbase(address: &str, height: i32, data: &str) -> Transaction {
+    Transaction::new_coinbase(address.to_string(), data.to_string(), height, Amount::ZERO).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_height_index() {
+        let address = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(address.clone(), store).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        let genesis = utxo_set.blockchain.tip.clone();
+        let b1 = utxo_set
+            .mine_block(vec![coinbase(&address, 1, "")])
+            .unwrap();
+        let b2 = utxo_set
+            .mine_block(vec![coinbase(&address, 2, "")])
+            .unwrap();
+
+        let bc = &utxo_set.blockchain;
+        assert_eq!(bc.get_block_hash(0).unwrap(), Some(genesis.clone()));
+        assert_eq!(bc.get_block_by_height(2).unwrap().get_hash(), b2.get_hash());
+        assert_eq!(bc.get_block_hash(3).unwrap(), None);
+        let hashes = bc.get_block_hashes_by_height(1, 10).unwrap();
+        assert_eq!(hashes, vec![b1.get_hash(), b2.get_hash()]);
+        assert_eq!(bc.get_confirmations(&b1.get_hash()).unwrap(), 2);
+
+        // a longer fork from the genesis block takes over the heights
+        let bits = bc.get_next_bits(&genesis).unwrap();
+        let mut prev = genesis.clone();
+        let mut fork = Vec::new();
+        for height in 1..4 {
+            let block =
+                Block::new_block(vec![coinbase(&address, height, "fork")], prev, height, bits)
+                    .unwrap();
+            prev = block.get_hash();
+            fork.push(prev.clone());
+            utxo_set.add_block(block).unwrap();
         }
-        None
+        let bc = &utxo_set.blockchain;
+        assert_eq!(bc.get_block_hashes_by_height(1, 10).unwrap(), fork);
+        let locator: Vec<_> = fork.iter().rev().chain([&genesis]).cloned().collect();
+        assert_eq!(bc.get_locator().unwrap(), locator);
+        assert_eq!(bc.get_confirmations(&b1.get_hash()).unwrap(), 0);
+        assert_eq!(
+            bc.get_blocks_by_height(3, 4).unwrap()[0].get_hash(),
+            fork[2]
+        );
+    }
+
+    #[test]
+    fn test_locator_heights() {
+        assert!(locator_heights(-1).is_empty());
+        assert_eq!(locator_heights(0), vec![0]);
+        assert_eq!(locator_heights(3), vec![3, 2, 1, 0]);
+        assert_eq!(
+            locator_heights(30),
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+    }
+
+    #[test]
+    fn test_tx_index() {
+        let address = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(address.clone(), store).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        let genesis = utxo_set.blockchain.get_block_by_height(0).unwrap();
+        let b1 = utxo_set
+            .mine_block(vec![coinbase(&address, 1, "")])
+            .unwrap();
+
+        // the index is built for the blocks already connected
+        utxo_set.blockchain.enable_txindex().unwrap();
+        let bc = &utxo_set.blockchain;
+        let location = |bc: &Blockchain, txid: &str| bc.get_store().get_tx_location(txid).unwrap();
+        let genesis_tx = &genesis.get_transaction()[0].id;
+        let b1_tx = &b1.get_transaction()[0].id;
+        assert_eq!(
+            location(bc, genesis_tx).unwrap().block_hash,
+            genesis.get_hash()
+        );
+        assert_eq!(location(bc, b1_tx).unwrap().block_hash, b1.get_hash());
+        assert_eq!(bc.find_transacton(b1_tx).unwrap().id, *b1_tx);
+
+        // blocks reorganized away leave the index
+        let bits = bc.get_next_bits(&genesis.get_hash()).unwrap();
+        let mut prev = genesis.get_hash();
+        let mut fork = Vec::new();
+        for height in 1..3 {
+            let block =
+                Block::new_block(vec![coinbase(&address, height, "fork")], prev, height, bits)
+                    .unwrap();
+            prev = block.get_hash();
+            fork.push(block.clone());
+            utxo_set.add_block(block).unwrap();
+        }
+        let bc = &utxo_set.blockchain;
+        assert!(location(bc, b1_tx).is_none());
+        assert!(bc.find_transacton(b1_tx).is_err());
+        let fork_tx = &fork[1].get_transaction()[0].id;
+        let (block, position) = bc.find_transaction_block(fork_tx).unwrap().unwrap();
+        assert_eq!((block.get_hash(), position), (fork[1].get_hash(), 0));
+    }
+
+    #[test]
+    fn test_spend_other_key() {
+        let wallet = Wallet::new();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(wallet.get_address(), store).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        let thief = Wallet::new();
+        let amount = Amount::from_coins(1);
+        let tx = Transaction::new_UTXO(
+            &wallet,
+            &thief.get_address(),
+            amount,
+            Amount::ZERO,
+            &utxo_set,
+        )
+        .unwrap();
+        // the thief signs the spend of the genesis coinbase with a key of its own
+        let mut stolen = tx.clone();
+        for vin in &mut stolen.vin {
+            vin.pub_key = thief.public_key.clone();
+            vin.signature.clear();
+        }
+        stolen.id = stolen.hash().unwrap();
+        let bc = &utxo_set.blockchain;
+        bc.sign_transacton(&mut stolen, &thief.secret_key).unwrap();
+        assert!(!bc.verify_transacton(&stolen).unwrap());
+
+        let bits = bc.get_next_bits(&bc.tip).unwrap();
+        let block_with = |tx: &Transaction| {
+            let txs = vec![coinbase(&thief.get_address(), 1, ""), tx.clone()];
+            Block::new_block(txs, bc.tip.clone(), 1, bits).unwrap()
+        };
+        bc.validate_block(&block_with(&tx), &utxo_set).unwrap();
+        let e = bc
+            .validate_block(&block_with(&stolen), &utxo_set)
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast::<BlockError>().unwrap(),
+            BlockError::BadSignature(id) if id == stolen.id
+        ));
+    }
+
+    #[test]
+    fn test_header_time() {
+        let address = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(address.clone(), store).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        for height in 1..3 {
+            utxo_set
+                .mine_block(vec![coinbase(&address, height, "")])
+                .unwrap();
+        }
+
+        let bc = &utxo_set.blockchain;
+        let median = bc.get_median_time_past(&bc.tip).unwrap();
+        assert_eq!(median, bc.get_block_by_height(1).unwrap().get_timestamp());
+        let header_at = |timestamp| {
+            let cbtx = coinbase(&address, 3, "");
+            let bits = bc.get_next_bits(&bc.tip).unwrap();
+            let mut block = Block::new_template(vec![cbtx], bc.tip.clone(), 3, bits).unwrap();
+            block.set_timestamp(timestamp);
+            block.mine().unwrap().get_header().clone()
+        };
+        let error = |timestamp| {
+            let e = bc.check_header(&header_at(timestamp)).unwrap_err();
+            e.downcast::<BlockError>().unwrap()
+        };
+
+        // a timestamp at the median of the last blocks could warp the clock back
+        assert!(matches!(error(median), BlockError::TimeTooOld));
+        assert_eq!(bc.check_header(&header_at(median + 1)).unwrap(), 3);
+        let future = now().unwrap() + MAX_FUTURE_BLOCK_TIME + 60_000;
+        assert!(matches!(error(future), BlockError::TimeTooNew));
     }
 }
@@ -1,12 +1,27 @@
+use crate::amount::Amount;
+use crate::banlist::{BanList, BANLIST_DB};
+use crate::block::TxProof;
 use crate::blockchain::Blockchain;
 use crate::errors::Result;
+use crate::peer::PeerConfig;
 use crate::server::Server;
-use crate::transaction::Transaction;
+use crate::spv::LightClient;
+use crate::transaction::{total_supply, Transaction};
 use crate::utxoset::UTXOSet;
-use crate::wallet::{Wallet, Wallets};
+use crate::wallet::Wallets;
 use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, ArgMatches, Command};
+use failure::format_err;
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{Duration, SystemTime};
+
+/// DATADIR_ENV is the environment variable that sets the data directory
+pub const DATADIR_ENV: &str = "BLOCKCHAIN_DATADIR";
+/// DEFAULT_DATADIR is the data directory used when none is set
+pub const DEFAULT_DATADIR: &str = "data";
 
 pub struct Cli {}
 
@@ -19,20 +34,92 @@ impl Cli {
             .version("0.1")
             .author("behrouz.r.fa@gmail.com")
             .about("blockchain in rust: a simple blockchain for learning")
+            .arg(
+                arg!(--datadir <DIR> "'directory the node keeps its data in'")
+                    .global(true),
+            )
+            .arg(
+                arg!(--txindex "'keep an index of the transactions of the chain by id'")
+                    .global(true),
+            )
+            .arg(
+                arg!(--addrindex "'keep an index of the outputs funding and spent by each address'")
+                    .global(true),
+            )
             .subcommand(Command::new("printchain").about("print all the chain blocks"))
             .subcommand(Command::new("createwallet").about("create a wallet"))
             .subcommand(Command::new("listaddresses").about("list all addresses"))
             .subcommand(Command::new("reindex").about("reindex UTXO"))
+            .subcommand(
+                Command::new("supply")
+                    .about("print the coins in existence and the subsidy schedule"),
+            )
             .subcommand(
                 Command::new("getbalance")
                     .about("get balance in the blochain")
-                    .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
+                    .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
+                    .arg(arg!(--light " 'use the transactions verified by the light client'")),
+            )
+            .subcommand(
+                Command::new("history")
+                    .about("print the outputs funding an address and their spending")
+                    .arg(arg!(<ADDRESS>"'The address to print the history of'")),
+            )
+            .subcommand(
+                Command::new("listunspent")
+                    .about("list the unspent outputs of an address")
+                    .arg(arg!(<ADDRESS>"'The address to list the outputs of'")),
+            )
+            .subcommand(
+                Command::new("gettxproof")
+                    .about("print a merkle proof that a transaction is in the chain")
+                    .arg(arg!(<TXID>"'The id of the transaction'")),
+            )
+            .subcommand(
+                Command::new("gettransaction")
+                    .about("print a transaction of the chain and its confirmations")
+                    .arg(arg!(<TXID>"'The id of the transaction'")),
+            )
+            .subcommand(
+                Command::new("getblockhash")
+                    .about("print the hash of the block at a height of the chain")
+                    .arg(arg!(<HEIGHT>"'The height of the block'")),
+            )
+            .subcommand(
+                Command::new("getblocks")
+                    .about("print the blocks of the chain from one height to another")
+                    .arg(arg!(<FROM>"'The height of the first block'"))
+                    .arg(arg!(<TO>"'The height of the last block'")),
             )
             .subcommand(
+                Command::new("verifytxproof")
+                    .about("check a merkle proof printed by gettxproof")
+                    .arg(arg!(<PROOF>"'The proof, in JSON'")),
+            )
+            .subcommand(peer_args(
                 Command::new("startnode")
                     .about("start the node server")
                     .arg(arg!(<PORT>"'the port server bind to locally'")),
+            ))
+            .subcommand(peer_args(
+                Command::new("startlight")
+                    .about("start a light node that syncs headers and verifies wallet transactions")
+                    .arg(arg!(<PORT>"'the port server bind to locally'")),
+            ))
+            .subcommand(
+                Command::new("getpeerinfo")
+                    .about("print the peers of a running node")
+                    .arg(arg!(<PORT>"'the port the node listens on locally'")),
             )
+            .subcommand(Command::new("listbanned").about("list the banned peer addresses"))
+            .subcommand(
+                Command::new("setban")
+                    .about("ban a peer address, or lift its ban")
+                    .arg(arg!(<IP>"'the IP address of the peer'"))
+                    .arg(arg!(<COMMAND>"'add or remove'"))
+                    .arg(arg!(--bantime <SECS> " 'how long the ban lasts, a day by default'")),
+            )
+            .subcommand(Command::new("clearbanned").about("lift every ban"))
             .subcommand(
                 Command::new("create")
                     .about("Create new blochain")
@@ -43,18 +130,36 @@ impl Cli {
                     .about("send  in the blockchain")
                     .arg(arg!(<FROM>" 'Source wallet address'"))
                     .arg(arg!(<TO>" 'Destination wallet address'"))
-                    .arg(arg!(<AMOUNT>" 'Destination wallet address'"))
-                    .arg(arg!(-m --mine " 'the from address mine immediately'")),
+                    .arg(arg!(<AMOUNT>" 'Amount to send, in coins with up to 8 decimals'"))
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .arg(arg!(--fee <FEE> " 'fee left for the miner'").default_value("0")),
             )
-            .subcommand(
+            .subcommand(peer_args(
                 Command::new("startminer")
                     .about("start the minner server")
                     .arg(arg!(<PORT>" 'the port server bind to locally'"))
-                    .arg(arg!(<ADDRESS>" 'wallet address'")),
-            )
+                    .arg(arg!(<ADDRESS>" 'wallet address'"))
+                    .arg(arg!(--threads <THREADS> " 'mining threads, all cores by default'")),
+            ))
             .get_matches();
 
-        if let Some(ref matches) = matches.subcommand_matches("startminer") {
+        // --datadir wins over the environment, which wins over the default
+        let datadir = match matches.get_one::<String>("datadir") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                PathBuf::from(env::var(DATADIR_ENV).unwrap_or_else(|_| DEFAULT_DATADIR.to_string()))
+            }
+        };
+        let txindex = matches.get_flag("txindex");
+        if txindex && matches.subcommand_matches("create").is_none() {
+            Blockchain::new(&datadir)?.enable_txindex()?;
+        }
+        let addrindex = matches.get_flag("addrindex");
+        if addrindex && matches.subcommand_matches("create").is_none() {
+            UTXOSet::new(Blockchain::new(&datadir)?)?.enable_addrindex()?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
                 port
             } else {
@@ -68,47 +173,145 @@ impl Cli {
                 println!("ADDRESS not supply!: usage");
                 exit(1)
             };
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            let bc = Blockchain::new(&datadir)?;
+            let utxo_set = UTXOSet::new(bc)?;
+            let mut server = Server::new(port, address, utxo_set)?;
+            if let Some(threads) = matches.get_one::<String>("threads") {
+                server.set_mining_threads(threads.parse()?);
+            }
+            server.set_peer_config(peer_config(matches, &datadir)?);
             server.start_server()?;
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("startnode") {
+        if let Some(matches) = matches.subcommand_matches("startnode") {
             if let Some(port) = matches.get_one::<String>("PORT") {
-                let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
+                let bc = Blockchain::new(&datadir)?;
+                let utxo_set = UTXOSet::new(bc)?;
+                let mut server = Server::new(port, "", utxo_set)?;
+                server.set_peer_config(peer_config(matches, &datadir)?);
                 server.start_server()?;
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+        if let Some(matches) = matches.subcommand_matches("startlight") {
+            if let Some(port) = matches.get_one::<String>("PORT") {
+                let pub_key_hashes = wallet_pub_key_hashes(&datadir)?;
+                let (chain, light) = LightClient::open(&datadir, pub_key_hashes)?;
+                let mut server = Server::new_light(port, chain, light)?;
+                server.set_peer_config(peer_config(matches, &datadir)?);
+                server.start_server()?;
+            }
         }
-        if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
+
+        if let Some(matches) = matches.subcommand_matches("getpeerinfo") {
+            if let Some(port) = matches.get_one::<String>("PORT") {
+                for info in Server::get_peer_info(&format!("localhost:{}", port))? {
+                    println!("{:#?}", info);
+                }
+            }
+        }
+
+        if matches.subcommand_matches("listbanned").is_some() {
+            cmd_list_banned(&datadir)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("setban") {
+            if let (Some(ip), Some(command)) = (
+                matches.get_one::<String>("IP"),
+                matches.get_one::<String>("COMMAND"),
+            ) {
+                let bantime = match matches.get_one::<String>("bantime") {
+                    Some(secs) => Some(Duration::from_secs(secs.parse()?)),
+                    None => None,
+                };
+                cmd_set_ban(&datadir, ip.parse()?, command, bantime)?;
+            }
+        }
+
+        if matches.subcommand_matches("clearbanned").is_some() {
+            ban_list(&datadir).clear()?;
+        }
+
+        if matches.subcommand_matches("createwallet").is_some() {
+            println!("address: {}", cmd_create_wallet(&datadir)?);
+        }
+        if matches.subcommand_matches("reindex").is_some() {
+            let count = cmd_reindex(&datadir)?;
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+        if matches.subcommand_matches("supply").is_some() {
+            cmd_supply(&datadir)?;
+        }
+
+        if matches.subcommand_matches("listaddresses").is_some() {
+            cmd_list_address(&datadir)?;
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("create") {
+        if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                cmd_create_blockchain(address)?;
+                cmd_create_blockchain(&datadir, address, txindex, addrindex)?;
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {}\n", balance);
+                let (balance, immature) = if matches.get_flag("light") {
+                    cmd_get_light_balance(&datadir, address)?
+                } else {
+                    cmd_get_balance(&datadir, address)?
+                };
+                println!("Balance: {}", balance);
+                println!("Immature: {}\n", immature);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("history") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                cmd_history(&datadir, address)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("listunspent") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                cmd_list_unspent(&datadir, address)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettxproof") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                cmd_get_tx_proof(&datadir, txid)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettransaction") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                cmd_get_transaction(&datadir, txid)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblockhash") {
+            if let Some(height) = matches.get_one::<String>("HEIGHT") {
+                cmd_get_block_hash(&datadir, height.parse()?)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblocks") {
+            if let (Some(from), Some(to)) = (
+                matches.get_one::<String>("FROM"),
+                matches.get_one::<String>("TO"),
+            ) {
+                cmd_get_blocks(&datadir, from.parse()?, to.parse()?)?;
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
+        if let Some(matches) = matches.subcommand_matches("verifytxproof") {
+            if let Some(proof) = matches.get_one::<String>("PROOF") {
+                cmd_verify_tx_proof(&datadir, proof)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.get_one::<String>("FROM") {
                 address
             } else {
@@ -123,17 +326,19 @@ impl Cli {
                 exit(1)
             };
 
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+            let amount: Amount = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
                 amount.parse()?
             } else {
                 println!("from not supply!: usage");
                 exit(1)
             };
 
+            let fee: Amount = matches.get_one::<String>("fee").unwrap().parse()?;
+
             if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+                cmd_send(&datadir, from, to, amount, fee, true)?;
             } else {
-                cmd_send(from, to, amount, false)?;
+                cmd_send(&datadir, from, to, amount, fee, false)?;
             }
 
             /*else {
@@ -141,25 +346,105 @@ impl Cli {
             }*/
         }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+        if matches.subcommand_matches("printchain").is_some() {
+            cmd_print_chain(&datadir)?;
         }
 
         Ok(())
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
+/// PeerArgs adds the options limiting the peers of a node to a command starting one
+fn peer_args(command: Command) -> Command {
+    command
+        .arg(arg!(--maxinbound <N> " 'most inbound peers to accept'"))
+        .arg(arg!(--maxoutbound <N> " 'most outbound peers to connect to'"))
+        .arg(arg!(--banscore <N> " 'ban score at which a misbehaving peer is banned'"))
+        .arg(arg!(--bantime <SECS> " 'how long a misbehaving peer is banned'"))
+        .arg(arg!(--banlocal " 'ban misbehaving peers on this machine too'"))
+}
+
+fn peer_config(matches: &ArgMatches, datadir: &Path) -> Result<PeerConfig> {
+    let mut config = PeerConfig {
+        banlist: Some(datadir.join(BANLIST_DB)),
+        ..PeerConfig::default()
+    };
+    if let Some(n) = matches.get_one::<String>("maxinbound") {
+        config.max_inbound = n.parse()?;
+    }
+    if let Some(n) = matches.get_one::<String>("maxoutbound") {
+        config.max_outbound = n.parse()?;
+    }
+    if let Some(n) = matches.get_one::<String>("banscore") {
+        config.ban_threshold = n.parse()?;
+    }
+    if let Some(secs) = matches.get_one::<String>("bantime") {
+        config.ban_time = Duration::from_secs(secs.parse()?);
+    }
+    config.ban_local = matches.get_flag("banlocal");
+    Ok(config)
+}
+
+fn ban_list(datadir: &Path) -> BanList {
+    BanList::new(Some(datadir.join(BANLIST_DB)))
+}
+
+fn cmd_list_banned(datadir: &Path) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    for ban in ban_list(datadir).list()? {
+        println!(
+            "{} banned for {}s more: {}",
+            ban.ip,
+            ban.until.saturating_sub(now),
+            ban.reason
+        );
+    }
+    Ok(())
+}
+
+/// CmdSetBan bans ip for bantime, a day if not given, or lifts its ban
+fn cmd_set_ban(datadir: &Path, ip: IpAddr, command: &str, bantime: Option<Duration>) -> Result<()> {
+    let bans = ban_list(datadir);
+    match command {
+        "add" => {
+            let bantime = bantime.unwrap_or(PeerConfig::default().ban_time);
+            bans.ban(ip, bantime, "set from the command line")?;
+        }
+        "remove" => {
+            if !bans.unban(ip)? {
+                return Err(format_err!("{} is not banned", ip));
+            }
+        }
+        _ => {
+            return Err(format_err!(
+                "Unknown setban command {}, use add or remove",
+                command
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn cmd_send(
+    datadir: &Path,
+    from: &str,
+    to: &str,
+    amount: Amount,
+    fee: Amount,
+    mine_now: bool,
+) -> Result<()> {
+    let bc = Blockchain::new(datadir)?;
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let wallets = Wallets::new(datadir)?;
     let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
+    let tx = Transaction::new_UTXO(wallet, to, amount, fee, &utxo_set)?;
     if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
-
-        utxo_set.update(&new_block)?;
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let cbtx =
+            Transaction::new_coinbase(from.to_string(), String::from("reward!"), height, fee)?;
+        utxo_set.mine_block(vec![cbtx, tx])?;
     } else {
         Server::send_transaction(&tx, utxo_set)?;
     }
@@ -168,53 +453,175 @@ fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
+fn cmd_create_wallet(datadir: &Path) -> Result<String> {
+    let mut ws = Wallets::new(datadir)?;
     let address = ws.create_wallet();
     ws.save_all()?;
     Ok(address)
 }
 
-fn cmd_reindex() -> Result<i32> {
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+fn cmd_reindex(datadir: &Path) -> Result<i32> {
+    let bc = Blockchain::new(datadir)?;
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     utxo_set.count_transactions()
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
+fn cmd_create_blockchain(
+    datadir: &Path,
+    address: &str,
+    txindex: bool,
+    addrindex: bool,
+) -> Result<()> {
     let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
+    let mut bc = Blockchain::create_blockchain(address, datadir)?;
+    if txindex {
+        bc.enable_txindex()?;
+    }
 
-    let utxo_set = UTXOSet { blockchain: bc };
-    utxo_set.reindex()?;
+    let utxo_set = UTXOSet::new(bc)?;
+    if addrindex {
+        utxo_set.enable_addrindex()?;
+    }
     println!("create blockchain");
     Ok(())
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
+fn cmd_get_balance(datadir: &Path, address: &str) -> Result<(Amount, Amount)> {
     let pub_key_hash = Address::decode(address).unwrap().body;
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+    let bc = Blockchain::new(datadir)?;
+    let utxo_set = UTXOSet::new(bc)?;
+    utxo_set.get_balance(&pub_key_hash)
+}
 
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
+fn cmd_get_light_balance(datadir: &Path, address: &str) -> Result<(Amount, Amount)> {
+    let pub_key_hash = Address::decode(address).unwrap().body;
+    let (chain, light) = LightClient::open(datadir, Vec::new())?;
+    light.get_balance(&chain, &pub_key_hash)
+}
+
+/// WalletPubKeyHashes returns the public key hashes of every address in the wallets
+fn wallet_pub_key_hashes(datadir: &Path) -> Result<Vec<Vec<u8>>> {
+    let ws = Wallets::new(datadir)?;
+    Ok(ws
+        .get_all_addresses()
+        .iter()
+        .map(|address| Address::decode(address).unwrap().body)
+        .collect())
+}
+
+fn cmd_get_tx_proof(datadir: &Path, txid: &str) -> Result<()> {
+    let bc = Blockchain::new(datadir)?;
+    let proof = bc.get_tx_proof(txid)?;
+    println!("{}", serde_json::to_string(&proof)?);
+    Ok(())
+}
+
+fn cmd_history(datadir: &Path, address: &str) -> Result<()> {
+    let pub_key_hash = Address::decode(address).unwrap().body;
+    let bc = Blockchain::new(datadir)?;
+    let utxo_set = UTXOSet::new(bc)?;
+    for event in utxo_set.get_history(&pub_key_hash)? {
+        match event.spent_by {
+            Some(spent_by) => println!(
+                "height {}: -{} {}:{} spent by {}",
+                event.height, event.value, event.txid, event.vout, spent_by
+            ),
+            None => println!(
+                "height {}: +{} {}:{}",
+                event.height, event.value, event.txid, event.vout
+            ),
+        }
     }
-    Ok(balance)
+    Ok(())
+}
+
+fn cmd_list_unspent(datadir: &Path, address: &str) -> Result<()> {
+    let pub_key_hash = Address::decode(address).unwrap().body;
+    let bc = Blockchain::new(datadir)?;
+    let utxo_set = UTXOSet::new(bc)?;
+    let height = utxo_set.blockchain.get_best_height()? + 1;
+    for (txid, vout, utxo) in utxo_set.list_unspent(&pub_key_hash)? {
+        let mature = if utxo.is_mature_at(height) {
+            ""
+        } else {
+            " (immature)"
+        };
+        println!(
+            "{}:{} {} at height {}{}",
+            txid, vout, utxo.output.value, utxo.height, mature
+        );
+    }
+    Ok(())
+}
+
+fn cmd_get_transaction(datadir: &Path, txid: &str) -> Result<()> {
+    let bc = Blockchain::new(datadir)?;
+    let (block, position) = match bc.find_transaction_block(txid)? {
+        Some(found) => found,
+        None => return Err(format_err!("Transaction is not found")),
+    };
+    println!("{:#?}", block.get_transaction()[position]);
+    println!("Block: {}", block.get_hash());
+    println!(
+        "Confirmations: {}",
+        bc.get_confirmations(&block.get_hash())?
+    );
+    Ok(())
+}
+
+fn cmd_get_block_hash(datadir: &Path, height: i32) -> Result<()> {
+    let bc = Blockchain::new(datadir)?;
+    match bc.get_block_hash(height)? {
+        Some(hash) => println!("{}", hash),
+        None => return Err(format_err!("No block at height {}", height)),
+    }
+    Ok(())
+}
+
+fn cmd_get_blocks(datadir: &Path, from: i32, to: i32) -> Result<()> {
+    let bc = Blockchain::new(datadir)?;
+    for b in bc.get_blocks_by_height(from, to.saturating_add(1))? {
+        println!("{:#?}", b);
+    }
+    Ok(())
+}
+
+fn cmd_verify_tx_proof(datadir: &Path, proof: &str) -> Result<()> {
+    let proof: TxProof = serde_json::from_str(proof)?;
+    let bc = Blockchain::new(datadir)?;
+    let header = bc.get_header(&proof.block_hash)?;
+    if !proof.verify(&header)? {
+        println!("Proof is invalid");
+        exit(1)
+    }
+    println!(
+        "Transaction {} is in block {} ({} confirmations)",
+        proof.transaction.id,
+        proof.block_hash,
+        bc.get_confirmations(&proof.block_hash)?
+    );
+    Ok(())
+}
+
+fn cmd_supply(datadir: &Path) -> Result<()> {
+    let utxo_set = UTXOSet::new(Blockchain::new(datadir)?)?;
+    let height = utxo_set.blockchain.get_best_height()?;
+    println!("Supply at height {}: {}", height, utxo_set.get_supply()?);
+    println!("Scheduled by subsidies: {}", total_supply(height));
+    Ok(())
 }
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
+fn cmd_print_chain(datadir: &Path) -> Result<()> {
+    let bc = Blockchain::new(datadir)?;
     for b in bc.iter() {
         println!("{:#?}", b);
     }
     Ok(())
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
+fn cmd_list_address(datadir: &Path) -> Result<()> {
+    let ws = Wallets::new(datadir)?;
     let addresses = ws.get_all_addresses();
     println!("addresses: ");
     for ad in addresses {
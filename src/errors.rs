@@ -1 +1,116 @@
+use failure::Fail;
+use std::fmt;
+
 pub type Result<T> = std::result::Result<T, failure::Error>;
+
+/// BlockError is the reason a block is rejected by consensus validation
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockError {
+    BadHash,
+    BadVersion,
+    BadProofOfWork,
+    BadBits,
+    TimeTooOld,
+    TimeTooNew,
+    UnknownParent,
+    InvalidParent,
+    NotOnTip,
+    BadHeight,
+    BadMerkleRoot,
+    BadCoinbase,
+    BadCoinbaseHeight,
+    BadCoinbaseValue,
+    BadTxid(String),
+    BadValue(String),
+    BadSignature(String),
+    MissingInput(String),
+    DoubleSpend(String),
+    ImmatureSpend(String),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::BadHash => write!(f, "block hash does not match its header"),
+            BlockError::BadVersion => write!(f, "block has an unknown version"),
+            BlockError::BadProofOfWork => write!(f, "block hash does not meet its target"),
+            BlockError::BadBits => write!(f, "block has incorrect difficulty bits"),
+            BlockError::TimeTooOld => write!(f, "block timestamp is not past the median time"),
+            BlockError::TimeTooNew => write!(f, "block timestamp is too far in the future"),
+            BlockError::UnknownParent => write!(f, "parent block is not found"),
+            BlockError::InvalidParent => write!(f, "parent block is invalid"),
+            BlockError::NotOnTip => write!(f, "block does not extend the tip"),
+            BlockError::BadHeight => write!(f, "block has incorrect height"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root does not match transactions"),
+            BlockError::BadCoinbase => write!(f, "block must start with its only coinbase"),
+            BlockError::BadCoinbaseHeight => write!(f, "coinbase does not commit to the height"),
+            BlockError::BadCoinbaseValue => write!(f, "coinbase pays more than subsidy and fees"),
+            BlockError::BadTxid(id) => write!(f, "transaction {} has an incorrect id", id),
+            BlockError::BadValue(id) => write!(f, "transaction {} spends more than it has", id),
+            BlockError::BadSignature(id) => write!(f, "transaction {} has a bad signature", id),
+            BlockError::MissingInput(id) => write!(f, "transaction {} spends a missing output", id),
+            BlockError::DoubleSpend(id) => write!(f, "transaction {} spends an output twice", id),
+            BlockError::ImmatureSpend(id) => {
+                write!(f, "transaction {} spends an immature coinbase", id)
+            }
+        }
+    }
+}
+
+impl Fail for BlockError {}
+
+/// WireError is the reason a frame read from a peer is rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum WireError {
+    BadMagic,
+    BadCommand,
+    Oversized(u32),
+    BadChecksum,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::BadMagic => write!(f, "frame does not start with the network magic"),
+            WireError::BadCommand => write!(f, "frame has a malformed command"),
+            WireError::Oversized(len) => write!(f, "frame payload of {} bytes is too large", len),
+            WireError::BadChecksum => write!(f, "frame payload does not match its checksum"),
+        }
+    }
+}
+
+impl Fail for WireError {}
+
+/// Misbehavior is a message of a peer that breaks the protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Misbehavior {
+    UnknownCommand(String),
+    BadPayload(String),
+    BeforeHandshake(String),
+    DuplicateVersion,
+    EmptyInv,
+    NotFound(String),
+    TooManyItems(String),
+    MissingInputs(String),
+    InvalidTransaction(String),
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Misbehavior::UnknownCommand(cmd) => write!(f, "unknown command {}", cmd),
+            Misbehavior::BadPayload(cmd) => write!(f, "{} message does not decode", cmd),
+            Misbehavior::BeforeHandshake(cmd) => write!(f, "{} message before the handshake", cmd),
+            Misbehavior::DuplicateVersion => write!(f, "version sent twice"),
+            Misbehavior::EmptyInv => write!(f, "inv message without items"),
+            Misbehavior::NotFound(id) => write!(f, "getdata for unknown {}", id),
+            Misbehavior::TooManyItems(cmd) => write!(f, "{} message asks for too much", cmd),
+            Misbehavior::MissingInputs(id) => {
+                write!(f, "transaction {} spends unknown outputs", id)
+            }
+            Misbehavior::InvalidTransaction(id) => write!(f, "transaction {} is invalid", id),
+        }
+    }
+}
+
+impl Fail for Misbehavior {}
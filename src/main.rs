@@ -1,12 +1,20 @@
+pub mod amount;
+pub mod banlist;
 pub mod block;
 pub mod blockchain;
 pub mod cli;
 pub mod errors;
+pub mod miner;
+pub mod peer;
+pub mod pow;
 mod server;
+pub mod spv;
+pub mod store;
 pub mod transaction;
 pub mod txn;
 pub mod utxoset;
 pub mod wallet;
+pub mod wire;
 
 use errors::Result;
 
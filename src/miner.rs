@@ -0,0 +1,187 @@
+//! multi-threaded proof of work miner
+//!
+//! The nonce space is split into one slice per worker thread. A worker that runs
+//! through its slice without finding a hash below the target rolls the block
+//! timestamp forward and starts over, so slices never overlap.
+
+use crate::block::Block;
+use crate::errors::Result;
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+/// CHECK_INTERVAL is the number of nonces a worker tries between checks for cancellation
+const CHECK_INTERVAL: u32 = 1 << 12;
+
+/// Miner searches for proof of work on several threads
+///
+/// Mining is tied to a job: Cancel invalidates every job handed out before it,
+/// which stops the workers mining for them.
+pub struct Miner {
+    threads: usize,
+    generation: AtomicU64,
+    hashrate: AtomicU64,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        Miner::new(threads)
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.max(1),
+            generation: AtomicU64::new(0),
+            hashrate: AtomicU64::new(0),
+        }
+    }
+
+    /// Job returns a token to mine on the current tip with
+    pub fn job(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Cancel aborts mining for every job handed out so far
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self, job: u64) -> bool {
+        self.generation.load(Ordering::SeqCst) != job
+    }
+
+    /// Hashrate returns the hashes per second measured while mining the last block
+    pub fn hashrate(&self) -> u64 {
+        self.hashrate.load(Ordering::Relaxed)
+    }
+
+    /// Mine searches for a nonce that makes the block meet its target
+    ///
+    /// It returns the block with its nonce and hash set, or None if the job was
+    /// cancelled first.
+    pub fn mine(&self, block: Block, job: u64) -> Result<Option<Block>> {
+        info!(
+            "Mining the block with bits {:#010x} on {} threads",
+            block.get_bits(),
+            self.threads
+        );
+        let start = Instant::now();
+        let found = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+
+        let slice = (u32::MAX as u64 + 1) / self.threads as u64;
+        let results: Vec<Result<Option<Block>>> = thread::scope(|s| {
+            let workers: Vec<_> = (0..self.threads as u64)
+                .map(|i| {
+                    let first = (i * slice) as u32;
+                    let last = if i + 1 == self.threads as u64 {
+                        u32::MAX
+                    } else {
+                        ((i + 1) * slice - 1) as u32
+                    };
+                    let (block, found, hashes) = (block.clone(), &found, &hashes);
+                    s.spawn(move || self.work(block, job, first, last, found, hashes))
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let hashes = hashes.load(Ordering::Relaxed);
+        if elapsed > 0.0 {
+            self.hashrate
+                .store((hashes as f64 / elapsed) as u64, Ordering::Relaxed);
+        }
+
+        let mut mined = None;
+        for result in results {
+            if let Some(block) = result? {
+                mined = Some(block);
+            }
+        }
+        match &mined {
+            Some(block) => info!(
+                "Mined block {} after {} hashes in {:.2}s ({} H/s)",
+                block.get_hash(),
+                hashes,
+                elapsed,
+                self.hashrate()
+            ),
+            None => info!("Mining cancelled after {} hashes", hashes),
+        }
+        Ok(mined)
+    }
+
+    /// Work tries the nonces from first to last, rolling the timestamp when they run out
+    fn work(
+        &self,
+        mut block: Block,
+        job: u64,
+        first: u32,
+        last: u32,
+        found: &AtomicBool,
+        hashes: &AtomicU64,
+    ) -> Result<Option<Block>> {
+        if self.is_cancelled(job) {
+            return Ok(None);
+        }
+        let mut nonce = first;
+        let mut tried = 0;
+        loop {
+            if tried == CHECK_INTERVAL {
+                hashes.fetch_add(tried as u64, Ordering::Relaxed);
+                tried = 0;
+                if found.load(Ordering::Relaxed) || self.is_cancelled(job) {
+                    return Ok(None);
+                }
+            }
+
+            block.set_nonce(nonce);
+            tried += 1;
+            if block.check_proof_of_work()? {
+                hashes.fetch_add(tried as u64, Ordering::Relaxed);
+                found.store(true, Ordering::Relaxed);
+                block.seal()?;
+                return Ok(Some(block));
+            }
+
+            if nonce == last {
+                block.roll_timestamp()?;
+                nonce = first;
+            } else {
+                nonce += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::pow::POW_LIMIT_BITS;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+
+    fn template() -> Block {
+        let address = Wallet::new().get_address();
+        let cbtx = Transaction::new_coinbase(address, String::new(), 0, Amount::ZERO).unwrap();
+        Block::new_template(vec![cbtx], String::new(), 0, POW_LIMIT_BITS).unwrap()
+    }
+
+    #[test]
+    fn test_mine() {
+        let miner = Miner::new(4);
+        let block = miner.mine(template(), miner.job()).unwrap().unwrap();
+        assert!(block.check_hash().unwrap());
+        assert!(block.check_proof_of_work().unwrap());
+
+        let job = miner.job();
+        miner.cancel();
+        assert!(miner.mine(template(), job).unwrap().is_none());
+    }
+}
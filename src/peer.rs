@@ -0,0 +1,620 @@
+//! peer manager
+//!
+//! The PeerManager keeps the connections of a node: inbound ones accepted by its
+//! listener and outbound ones it opens, up to a maximum of each. Messages to a peer go
+//! over its connection, which stays open for as long as both ends keep it. An address
+//! that could not be connected to, or that dropped the connection before the handshake,
+//! is retried with exponential backoff.
+//!
+//! Peers that completed the handshake are pinged now and then; the round trip of each
+//! answered ping is recorded, and a peer that does not answer in time is disconnected.
+//!
+//! A peer that misbehaves gathers ban score; once it reaches the ban threshold the IP
+//! address of the peer is banned and every connection with it closed.
+
+use crate::banlist::BanList;
+use crate::errors::Result;
+use crate::wire::write_frame;
+use failure::format_err;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Shutdown, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// CONNECT_TIMEOUT is how long opening an outbound connection may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// WRITE_TIMEOUT is how long a peer may take to accept a frame
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// RECONNECT_DELAY is the wait after the first failure to connect to an address;
+/// it doubles with each further failure up to MAX_RECONNECT_DELAY
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// PeerConfig limits the connections of a node and sets how misbehaving peers are
+/// banned
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    /// ban score at which a peer is banned
+    pub ban_threshold: u32,
+    pub ban_time: Duration,
+    /// whether peers on this machine are banned too; by default they only gather ban
+    /// score, so a misbehaving local peer cannot cut the node off from the others and
+    /// the CLI
+    pub ban_local: bool,
+    /// db the bans are kept in; without one they are lost when the node stops
+    pub banlist: Option<PathBuf>,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        PeerConfig {
+            max_inbound: 16,
+            max_outbound: 8,
+            ban_threshold: 100,
+            ban_time: Duration::from_secs(24 * 60 * 60),
+            ban_local: false,
+            banlist: None,
+        }
+    }
+}
+
+/// PeerInfo is what a node knows about a connected peer
+///
+/// Times are in seconds since the Unix epoch, except the ping time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub id: u64,
+    /// address the peer listens on, or the address of its socket until it tells it
+    pub addr: String,
+    pub inbound: bool,
+    /// protocol version, once the peer sent its version
+    pub version: Option<i32>,
+    pub services: u64,
+    pub user_agent: String,
+    pub best_height: i32,
+    /// whether the peer sent both its version and a verack for ours
+    pub handshake: bool,
+    pub connected_at: u64,
+    pub last_seen: u64,
+    /// round trip of the last answered ping
+    pub ping_time: Option<Duration>,
+    pub ban_score: u32,
+}
+
+struct Peer {
+    info: PeerInfo,
+    /// IP address of the socket, which a ban applies to
+    ip: IpAddr,
+    stream: Arc<Mutex<TcpStream>>,
+    version_sent: bool,
+    /// nonce of the ping waiting for a pong
+    ping_nonce: Option<u64>,
+    /// when the last ping was sent
+    pinged_at: Option<Instant>,
+}
+
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// PeerManager keeps the connections to the peers of a node
+pub struct PeerManager {
+    config: PeerConfig,
+    peers: Mutex<HashMap<u64, Peer>>,
+    backoff: Mutex<HashMap<String, Backoff>>,
+    bans: BanList,
+    next_id: AtomicU64,
+}
+
+impl PeerManager {
+    pub fn new(config: PeerConfig) -> PeerManager {
+        PeerManager {
+            bans: BanList::new(config.banlist.clone()),
+            config,
+            peers: Mutex::new(HashMap::new()),
+            backoff: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// AddInbound registers a connection accepted by the listener
+    ///
+    /// It returns the id of the peer, or None, closing the connection, if there are
+    /// already max_inbound inbound peers or the address of the peer is banned.
+    pub fn add_inbound(&self, stream: TcpStream) -> Result<Option<u64>> {
+        let addr = stream.peer_addr()?;
+        if self.count(true) >= self.config.max_inbound || self.is_banned(addr.ip()) {
+            info!("dropping inbound connection from {}", addr);
+            stream.shutdown(Shutdown::Both).ok();
+            return Ok(None);
+        }
+        Ok(Some(self.add(&addr.to_string(), stream, true)?))
+    }
+
+    /// Connect opens an outbound connection to addr
+    ///
+    /// It returns the id of the peer and the stream to read its messages from, or None
+    /// if there are already max_outbound outbound peers, addr is waiting to be retried,
+    /// it cannot be connected to or it is banned.
+    pub fn connect(&self, addr: &str) -> Result<Option<(u64, TcpStream)>> {
+        if self.count(false) >= self.config.max_outbound {
+            return Ok(None);
+        }
+        if let Some(backoff) = self.backoff.lock().unwrap().get(addr) {
+            if backoff.retry_at > Instant::now() {
+                return Ok(None);
+            }
+        }
+
+        let stream = match open(addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let delay = self.fail(addr);
+                info!("cannot connect to {}: {}, retry in {:?}", addr, e, delay);
+                return Ok(None);
+            }
+        };
+        if self.is_banned(stream.peer_addr()?.ip()) {
+            info!("not connecting to banned {}", addr);
+            stream.shutdown(Shutdown::Both).ok();
+            self.fail(addr);
+            return Ok(None);
+        }
+        self.backoff.lock().unwrap().remove(addr);
+        let reader = stream.try_clone()?;
+        let id = self.add(addr, stream, false)?;
+        info!("connected to peer {} at {}", id, addr);
+        Ok(Some((id, reader)))
+    }
+
+    /// Fail records a failed connection to addr and returns how long to wait before
+    /// trying it again
+    fn fail(&self, addr: &str) -> Duration {
+        let mut backoff = self.backoff.lock().unwrap();
+        let entry = backoff.entry(addr.to_string()).or_insert(Backoff {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+        entry.failures += 1;
+        let delay = RECONNECT_DELAY
+            .saturating_mul(1 << (entry.failures - 1).min(16))
+            .min(MAX_RECONNECT_DELAY);
+        entry.retry_at = Instant::now() + delay;
+        delay
+    }
+
+    /// IsBanned returns whether ip is banned, taking it as not banned if the bans
+    /// cannot be read
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.is_banned(ip).unwrap_or_else(|e| {
+            warn!("cannot read the bans: {}", e);
+            false
+        })
+    }
+
+    fn add(&self, addr: &str, stream: TcpStream, inbound: bool) -> Result<u64> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let ip = stream.peer_addr()?.ip();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let now = now();
+        let info = PeerInfo {
+            id,
+            addr: addr.to_string(),
+            inbound,
+            version: None,
+            services: 0,
+            user_agent: String::new(),
+            best_height: -1,
+            handshake: false,
+            connected_at: now,
+            last_seen: now,
+            ping_time: None,
+            ban_score: 0,
+        };
+        let peer = Peer {
+            info,
+            ip,
+            stream: Arc::new(Mutex::new(stream)),
+            version_sent: false,
+            ping_nonce: None,
+            pinged_at: None,
+        };
+        self.peers.lock().unwrap().insert(id, peer);
+        Ok(id)
+    }
+
+    /// Remove closes the connection to a peer and forgets it
+    ///
+    /// An outbound peer that did not complete the handshake is retried with backoff,
+    /// like an address that could not be connected to.
+    pub fn remove(&self, id: u64) {
+        let peer = match self.peers.lock().unwrap().remove(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        info!("disconnect peer {} at {}", id, peer.info.addr);
+        peer.stream.lock().unwrap().shutdown(Shutdown::Both).ok();
+        if !peer.info.inbound && !peer.info.handshake {
+            self.fail(&peer.info.addr);
+        }
+    }
+
+    /// Find returns the id of a peer connected with addr
+    pub fn find(&self, addr: &str) -> Option<u64> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .values()
+            .filter(|peer| peer.info.addr == addr)
+            .map(|peer| peer.info.id)
+            .min()
+    }
+
+    /// Send writes a message to a peer
+    ///
+    /// Frames are written whole: only one thread writes to a peer at a time.
+    pub fn send(&self, id: u64, cmd: &str, payload: &[u8]) -> Result<()> {
+        let stream = match self.peers.lock().unwrap().get(&id) {
+            Some(peer) => Arc::clone(&peer.stream),
+            None => return Err(format_err!("Peer {} is not connected", id)),
+        };
+        let mut stream = stream.lock().unwrap();
+        write_frame(&mut *stream, cmd, payload)
+    }
+
+    /// Count returns the number of inbound or outbound peers
+    pub fn count(&self, inbound: bool) -> usize {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .values()
+            .filter(|peer| peer.info.inbound == inbound)
+            .count()
+    }
+
+    fn update<F: FnOnce(&mut PeerInfo)>(&self, id: u64, f: F) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&id) {
+            f(&mut peer.info);
+        }
+    }
+
+    /// SetAddr sets the address a peer listens on, unless another peer has it
+    pub fn set_addr(&self, id: u64, addr: &str) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers
+            .values()
+            .any(|peer| peer.info.id != id && peer.info.addr == addr)
+        {
+            return false;
+        }
+        match peers.get_mut(&id) {
+            Some(peer) => {
+                peer.info.addr = addr.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// SetVersion records the version message of a peer
+    pub fn set_version(
+        &self,
+        id: u64,
+        version: i32,
+        services: u64,
+        user_agent: &str,
+        best_height: i32,
+    ) {
+        self.update(id, |info| {
+            info.version = Some(version);
+            info.services = services;
+            info.user_agent = user_agent.to_string();
+            info.best_height = best_height;
+        });
+    }
+
+    /// SetVersionSent records that our version was sent to a peer, returning whether it
+    /// already was
+    pub fn set_version_sent(&self, id: u64) -> bool {
+        match self.peers.lock().unwrap().get_mut(&id) {
+            Some(peer) => std::mem::replace(&mut peer.version_sent, true),
+            None => false,
+        }
+    }
+
+    /// SetVerack records a verack from a peer, which completes the handshake
+    ///
+    /// The peer has to send its version first: a verack before it is ignored.
+    pub fn set_verack(&self, id: u64) {
+        self.update(id, |info| info.handshake = info.version.is_some());
+    }
+
+    /// GetPeer returns the state of a peer, None once it is disconnected
+    pub fn get_peer(&self, id: u64) -> Option<PeerInfo> {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&id).map(|peer| peer.info.clone())
+    }
+
+    /// IsLocal returns whether a peer connected from this machine
+    pub fn is_local(&self, id: u64) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&id).is_some_and(|peer| peer.ip.is_loopback())
+    }
+
+    /// SetBestHeight raises the best height of a peer, which sent a block at height
+    pub fn set_best_height(&self, id: u64, height: i32) {
+        self.update(id, |info| info.best_height = info.best_height.max(height));
+    }
+
+    /// Touch records that a message was received from a peer
+    pub fn touch(&self, id: u64) {
+        self.update(id, |info| info.last_seen = now());
+    }
+
+    /// GetPeerInfo returns the state of every connected peer, oldest first
+    pub fn get_peer_info(&self) -> Vec<PeerInfo> {
+        let mut infos: Vec<PeerInfo> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|peer| peer.info.clone())
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Misbehaving adds score to the ban score of a peer, returning true if it reached
+    /// the ban threshold and was disconnected
+    ///
+    /// The address of a peer reaching the threshold is banned for the configured time,
+    /// and every peer with it disconnected. A loopback address is only banned with
+    /// ban_local set; without it just the peer is disconnected.
+    pub fn misbehaving(&self, id: u64, score: u32, reason: &str) -> Result<bool> {
+        let ip = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = match peers.get_mut(&id) {
+                Some(peer) => peer,
+                None => return Ok(false),
+            };
+            peer.info.ban_score = peer.info.ban_score.saturating_add(score);
+            if peer.info.ban_score < self.config.ban_threshold {
+                return Ok(false);
+            }
+            if !peer.ip.is_loopback() || self.config.ban_local {
+                Some(peer.ip)
+            } else {
+                None
+            }
+        };
+        let ip = match ip {
+            Some(ip) => ip,
+            None => {
+                warn!("disconnecting local peer {} without a ban: {}", id, reason);
+                self.remove(id);
+                return Ok(true);
+            }
+        };
+        warn!("banning {} for {:?}: {}", ip, self.config.ban_time, reason);
+        self.bans.ban(ip, self.config.ban_time, reason)?;
+        self.remove_banned()?;
+        Ok(true)
+    }
+
+    /// RemoveBanned disconnects the peers whose address is banned, including by
+    /// another process changing the bans
+    pub fn remove_banned(&self) -> Result<()> {
+        let banned: HashSet<IpAddr> = self.bans.list()?.into_iter().map(|ban| ban.ip).collect();
+        let ids: Vec<u64> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| banned.contains(&peer.ip))
+            .map(|peer| peer.info.id)
+            .collect();
+        for id in ids {
+            self.remove(id);
+        }
+        Ok(())
+    }
+
+    /// StartPings returns the peers to ping, with the nonce of each ping
+    ///
+    /// A peer is pinged once it completed the handshake, then interval after each ping,
+    /// as long as it answered the last one.
+    pub fn start_pings(&self, interval: Duration) -> Vec<(u64, u64)> {
+        let mut pings = Vec::new();
+        for peer in self.peers.lock().unwrap().values_mut() {
+            let due = peer.pinged_at.is_none_or(|at| at.elapsed() >= interval);
+            if peer.info.handshake && peer.ping_nonce.is_none() && due {
+                let nonce = rand::random();
+                peer.ping_nonce = Some(nonce);
+                peer.pinged_at = Some(Instant::now());
+                pings.push((peer.info.id, nonce));
+            }
+        }
+        pings
+    }
+
+    /// SetPong records the pong of a peer, returning false if it answers no ping of ours
+    pub fn set_pong(&self, id: u64, nonce: u64) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = match peers.get_mut(&id) {
+            Some(peer) if peer.ping_nonce == Some(nonce) => peer,
+            _ => return false,
+        };
+        peer.ping_nonce = None;
+        peer.info.ping_time = peer.pinged_at.map(|at| at.elapsed());
+        true
+    }
+
+    /// RemoveStale disconnects the peers that did not complete the handshake within
+    /// handshake_timeout of connecting, or did not answer a ping within ping_timeout
+    ///
+    /// An outbound peer that did not answer is retried with backoff.
+    pub fn remove_stale(&self, handshake_timeout: Duration, ping_timeout: Duration) {
+        let deadline = now().saturating_sub(handshake_timeout.as_secs());
+        let mut stale = Vec::new();
+        for peer in self.peers.lock().unwrap().values() {
+            let info = &peer.info;
+            let unanswered = peer.ping_nonce.is_some()
+                && peer.pinged_at.is_some_and(|at| at.elapsed() > ping_timeout);
+            if unanswered || (!info.handshake && info.connected_at < deadline) {
+                stale.push((info.id, info.addr.clone(), !info.inbound && unanswered));
+            }
+        }
+        for (id, addr, retry) in stale {
+            self.remove(id);
+            if retry {
+                self.fail(&addr);
+            }
+        }
+    }
+}
+
+fn open(addr: &str) -> Result<TcpStream> {
+    let socket = match addr.to_socket_addrs()?.next() {
+        Some(socket) => socket,
+        None => return Err(format_err!("Cannot resolve {}", addr)),
+    };
+    Ok(TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT)?)
+}
+
+/// Now returns the time in seconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::read_frame;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peers = PeerManager::new(PeerConfig {
+            max_inbound: 1,
+            max_outbound: 1,
+            ..PeerConfig::default()
+        });
+
+        // messages to a peer go over one connection
+        let (id, _) = peers.connect(&addr).unwrap().unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        assert_eq!(peers.find(&addr), Some(id));
+        peers.send(id, "ping", b"1").unwrap();
+        peers.send(id, "ping", b"2").unwrap();
+        assert_eq!(read_frame(&mut accepted).unwrap().unwrap().1, b"1");
+        assert_eq!(read_frame(&mut accepted).unwrap().unwrap().1, b"2");
+        assert!(peers.connect(&addr).unwrap().is_none());
+
+        let _inbound = (
+            TcpStream::connect(&addr).unwrap(),
+            TcpStream::connect(&addr).unwrap(),
+        );
+        let (accepted, _) = listener.accept().unwrap();
+        assert!(peers.add_inbound(accepted).unwrap().is_some());
+        let (accepted, _) = listener.accept().unwrap();
+        assert!(peers.add_inbound(accepted).unwrap().is_none());
+
+        peers.set_version(id, 1, 0, "/test/", 5);
+        peers.set_best_height(id, 3);
+        assert!(!peers.set_version_sent(id));
+        assert!(peers.set_version_sent(id));
+        peers.set_verack(id);
+        let info = &peers.get_peer_info()[0];
+        assert_eq!((info.id, info.version, info.best_height), (id, Some(1), 5));
+        assert!(info.handshake);
+        peers.remove(id);
+        assert_eq!(peers.find(&addr), None);
+        assert!(peers.send(id, "ping", b"").is_err());
+    }
+
+    #[test]
+    fn test_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let peers = PeerManager::new(PeerConfig::default());
+        let (id, _) = peers.connect(&addr).unwrap().unwrap();
+
+        // only peers that completed the handshake are pinged
+        assert!(peers.start_pings(Duration::ZERO).is_empty());
+        peers.set_version(id, 1, 0, "/test/", 0);
+        peers.set_verack(id);
+        let pings = peers.start_pings(Duration::ZERO);
+        assert_eq!(pings.len(), 1);
+        let (_, nonce) = pings[0];
+        // no new ping while one is waiting for its pong
+        assert!(peers.start_pings(Duration::ZERO).is_empty());
+        assert!(!peers.set_pong(id, nonce.wrapping_add(1)));
+        assert!(peers.set_pong(id, nonce));
+        assert!(peers.get_peer(id).unwrap().ping_time.is_some());
+        assert!(!peers.set_pong(id, nonce));
+        assert!(peers.start_pings(Duration::from_secs(60)).is_empty());
+
+        // a peer that does not answer is disconnected, and its address held back
+        assert_eq!(peers.start_pings(Duration::ZERO).len(), 1);
+        peers.remove_stale(Duration::from_secs(60), Duration::from_secs(60));
+        assert!(peers.get_peer(id).is_some());
+        thread::sleep(Duration::from_millis(10));
+        peers.remove_stale(Duration::from_secs(60), Duration::ZERO);
+        assert!(peers.get_peer(id).is_none());
+        assert!(peers.connect(&addr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let peers = PeerManager::new(PeerConfig::default());
+        assert!(peers.connect(&addr).unwrap().is_none());
+        assert_eq!(peers.fail(&addr), RECONNECT_DELAY * 2);
+        assert!(peers.backoff.lock().unwrap()[&addr].retry_at > Instant::now());
+        for _ in 0..20 {
+            peers.fail(&addr);
+        }
+        assert_eq!(peers.fail(&addr), MAX_RECONNECT_DELAY);
+    }
+
+    #[test]
+    fn test_ban_local() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let config = PeerConfig {
+            ban_threshold: 10,
+            ..PeerConfig::default()
+        };
+
+        // a local peer is disconnected, but not banned
+        let peers = PeerManager::new(config.clone());
+        let (id, _) = peers.connect(&addr).unwrap().unwrap();
+        assert!(!peers.misbehaving(id, 5, "test").unwrap());
+        assert_eq!(peers.get_peer(id).unwrap().ban_score, 5);
+        assert!(peers.misbehaving(id, 5, "test").unwrap());
+        assert!(peers.get_peer(id).is_none());
+        assert!(peers.bans.list().unwrap().is_empty());
+
+        let peers = PeerManager::new(PeerConfig {
+            ban_local: true,
+            ..config
+        });
+        let (id, _) = peers.connect(&addr).unwrap().unwrap();
+        assert!(peers.misbehaving(id, 10, "test").unwrap());
+        assert!(peers.get_peer(id).is_none());
+        assert_eq!(peers.bans.list().unwrap().len(), 1);
+    }
+}
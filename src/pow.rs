@@ -0,0 +1,130 @@
+//! proof of work targets
+//!
+//! Difficulty is carried in blocks as compact "bits": the top byte is the size of the
+//! target in bytes and the low three bytes are its most significant digits, so
+//! `0x1f00ffff` is `0xffff * 256^(0x1f - 3)`. A block hash, read as a big-endian
+//! 256-bit number, has to be lower than or equal to the target.
+
+use primitive_types::{U256, U512};
+
+/// POW_LIMIT_BITS is the easiest target a block can have
+pub const POW_LIMIT_BITS: u32 = 0x2000ffff;
+
+/// CompactToTarget expands compact bits to the full 256-bit target
+///
+/// Targets with the sign bit set or that do not fit in 256 bits decode to zero,
+/// which no hash can meet.
+pub fn compact_to_target(bits: u32) -> U256 {
+    let size = bits >> 24;
+    let mantissa = bits & 0x007fffff;
+    if bits & 0x00800000 != 0 || mantissa == 0 {
+        return U256::zero();
+    }
+    if size <= 3 {
+        return U256::from(mantissa >> (8 * (3 - size)));
+    }
+    let shift = 8 * (size - 3);
+    if 32 - mantissa.leading_zeros() + shift > 256 {
+        return U256::zero();
+    }
+    U256::from(mantissa) << shift
+}
+
+/// TargetToCompact encodes a target as compact bits, dropping all but the top digits
+pub fn target_to_compact(target: U256) -> u32 {
+    let mut size = (target.bits() as u32).div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | size << 24
+}
+
+/// CheckProofOfWork checks a block hash against the target encoded in bits
+pub fn check_proof_of_work(hash: &[u8], bits: u32) -> bool {
+    let target = compact_to_target(bits);
+    if target.is_zero() || target > compact_to_target(POW_LIMIT_BITS) {
+        return false;
+    }
+    U256::from_big_endian(hash) <= target
+}
+
+/// BlockWork returns the expected number of hashes needed to find a block with these bits
+pub fn block_work(bits: u32) -> U256 {
+    let target = compact_to_target(bits);
+    if target.is_zero() {
+        return U256::zero();
+    }
+    // 2^256 / (target + 1), computed without overflowing 256 bits
+    (!target / (target + 1)) + 1
+}
+
+/// Retarget returns the bits for the next window of blocks
+///
+/// `actual` is how long the last window took and `expected` how long it should have taken,
+/// both in milliseconds. The target scales by `actual / expected`, limited to a factor of 4
+/// in either direction and never easier than POW_LIMIT_BITS.
+pub fn retarget(bits: u32, actual: u128, expected: u128) -> u32 {
+    let actual = actual.clamp(expected / 4, expected * 4);
+    let target = U512::from(compact_to_target(bits)) * U512::from(actual) / U512::from(expected);
+    let limit = compact_to_target(POW_LIMIT_BITS);
+    let target = match U256::try_from(target) {
+        Ok(t) if t <= limit => t,
+        _ => limit,
+    };
+    target_to_compact(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compact() {
+        assert_eq!(
+            compact_to_target(0x1d00ffff),
+            U256::from(0xffff) << (8 * (0x1d - 3))
+        );
+        assert_eq!(compact_to_target(0x01003456), U256::zero());
+        assert_eq!(compact_to_target(0x02008000), U256::from(0x80));
+        assert_eq!(compact_to_target(0x04923456), U256::zero());
+        assert_eq!(compact_to_target(0xff123456), U256::zero());
+        for bits in [
+            0x1d00ffff,
+            0x1f00ffff,
+            POW_LIMIT_BITS,
+            0x1b0404cb,
+            0x03123456,
+        ] {
+            assert_eq!(target_to_compact(compact_to_target(bits)), bits);
+        }
+        assert_eq!(target_to_compact(U256::from(0x80)), 0x02008000);
+    }
+
+    #[test]
+    fn test_work_and_retarget() {
+        assert_eq!(block_work(0x2000ffff), U256::from(256));
+        assert!(block_work(0x1f00ffff) > block_work(0x2000ffff));
+
+        let expected = 90_000;
+        assert_eq!(retarget(0x1f00ffff, expected, expected), 0x1f00ffff);
+        assert_eq!(retarget(0x1f00ffff, expected / 2, expected), 0x1e7fff80);
+        assert_eq!(retarget(0x1f00ffff, 0, expected), 0x1e3fffc0);
+        assert_eq!(retarget(0x1f00ffff, expected * 100, expected), 0x1f03fffc);
+        assert_eq!(
+            retarget(POW_LIMIT_BITS, expected * 2, expected),
+            POW_LIMIT_BITS
+        );
+
+        let mut hash = [0xffu8; 32];
+        assert!(!check_proof_of_work(&hash, 0x1f00ffff));
+        hash[..3].copy_from_slice(&[0, 0, 0]);
+        assert!(check_proof_of_work(&hash, 0x1f00ffff));
+        assert!(!check_proof_of_work(&[0u8; 32], 0x2100ffff));
+    }
+}
@@ -1,19 +1,25 @@
 //! server of Blockchain
 
 use super::*;
+use crate::amount::Amount;
 use crate::block::*;
+use crate::blockchain::Blockchain;
+use crate::errors::{BlockError, Misbehavior, WireError};
+use crate::miner::Miner;
+use crate::peer::{PeerConfig, PeerInfo, PeerManager};
+use crate::spv::LightClient;
 use crate::transaction::*;
 use crate::utxoset::*;
+use crate::wire::{read_frame, write_frame};
 use bincode::{deserialize, serialize};
 use failure::format_err;
-use log::{debug, info};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
@@ -21,9 +27,45 @@ enum Message {
     Version(Versionmsg),
     Tx(Txmsg),
     GetData(GetDatamsg),
-    GetBlock(GetBlocksmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+    GetTxProof(GetTxProofmsg),
+    TxProof(TxProofmsg),
+    GetAddrTxs(GetAddrTxsmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    GetPeerInfo,
+}
+
+impl Message {
+    /// AddrFrom returns the address the sender listens on, if the message carries it
+    fn addr_from(&self) -> Option<&str> {
+        let addr_from = match self {
+            Message::Addr(_)
+            | Message::Verack
+            | Message::Ping(_)
+            | Message::Pong(_)
+            | Message::GetPeerInfo => return None,
+            Message::Version(msg) => &msg.addr_from,
+            Message::Tx(msg) => &msg.addr_from,
+            Message::GetData(msg) => &msg.addr_from,
+            Message::GetHeaders(msg) => &msg.addr_from,
+            Message::Headers(msg) => &msg.addr_from,
+            Message::GetTxProof(msg) => &msg.addr_from,
+            Message::TxProof(msg) => &msg.addr_from,
+            Message::GetAddrTxs(msg) => &msg.addr_from,
+            Message::Inv(msg) => &msg.addr_from,
+            Message::Block(msg) => &msg.addr_from,
+        };
+        // a client that does not listen sends an empty address
+        if addr_from.is_empty() {
+            return None;
+        }
+        Some(addr_from)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -33,8 +75,15 @@ struct Blockmsg {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetBlocksmsg {
+struct GetHeadersmsg {
     addr_from: String,
+    locator: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Headersmsg {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,6 +93,24 @@ struct GetDatamsg {
     id: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetTxProofmsg {
+    addr_from: String,
+    txids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetAddrTxsmsg {
+    addr_from: String,
+    pub_key_hashes: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TxProofmsg {
+    addr_from: String,
+    proofs: Vec<TxProof>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Invmsg {
     addr_from: String,
@@ -61,25 +128,73 @@ struct Txmsg {
 struct Versionmsg {
     addr_from: String,
     version: i32,
+    services: u64,
+    user_agent: String,
     best_height: i32,
+    /// random number of the sending node, which tells a connection to itself
+    nonce: u64,
 }
 
+impl Versionmsg {
+    fn new(addr_from: &str, services: u64, best_height: i32, nonce: u64) -> Versionmsg {
+        Versionmsg {
+            addr_from: addr_from.to_string(),
+            version: VERSION,
+            services,
+            user_agent: String::from(USER_AGENT),
+            best_height,
+            nonce,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Server {
     node_address: String,
     mining_address: String,
+    miner: Arc<Miner>,
+    peers: Arc<PeerManager>,
+    /// nonce sent in the version messages of this node
+    nonce: u64,
+    /// mine_requests wakes up the mining thread once it is started
+    mine_requests: Arc<Mutex<Option<mpsc::Sender<()>>>>,
     inner: Arc<Mutex<ServerInner>>,
 }
 
+/// ServerInner is the state shared by the threads of a server
+///
+/// A light node has no block bodies: `utxo` only holds its chain of headers.
 struct ServerInner {
     known_nodes: HashSet<String>,
     utxo: UTXOSet,
     blocks_in_transit: Vec<String>,
     mempool: HashMap<String, Transaction>,
+    light: Option<LightClient>,
 }
 
 const KNOWN_NODE1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+/// VERSION is the protocol version of this node; peers older than MIN_VERSION are
+/// disconnected
+const VERSION: i32 = 2;
+const MIN_VERSION: i32 = 2;
+/// NODE_NETWORK is the service flag of a node that serves full blocks
+const NODE_NETWORK: u64 = 1;
+const USER_AGENT: &str = "/blockchain-rust:0.1/";
+/// HANDSHAKE_TIMEOUT is how long a peer has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// PING_INTERVAL is how often a peer is pinged; it is disconnected if it does not
+/// answer within PING_TIMEOUT
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+/// MAX_HEADERS is the most headers sent in reply to a getheaders message
+const MAX_HEADERS: usize = 2000;
+/// MAX_ADDR_TX_KEYS is the most public key hashes a getaddrtxs message may ask about
+const MAX_ADDR_TX_KEYS: usize = 100;
+/// MAX_TX_PROOF_IDS is the most transactions a gettxproof message may ask about, as
+/// without the transaction index each one is looked up by scanning the chain
+const MAX_TX_PROOF_IDS: usize = 16;
+/// PEER_CHECK_INTERVAL is how often a node connects to known nodes it has no connection to
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 impl Server {
     pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
@@ -88,21 +203,39 @@ impl Server {
         Ok(Server {
             node_address: String::from("localhost:") + port,
             mining_address: miner_address.to_string(),
+            miner: Arc::new(Miner::default()),
+            peers: Arc::new(PeerManager::new(PeerConfig::default())),
+            nonce: rand::random(),
+            mine_requests: Arc::new(Mutex::new(None)),
             inner: Arc::new(Mutex::new(ServerInner {
                 known_nodes: node_set,
                 utxo,
                 blocks_in_transit: Vec::new(),
                 mempool: HashMap::new(),
+                light: None,
             })),
         })
     }
 
+    /// NewLight creates a light node server that syncs the headers of chain
+    pub fn new_light(port: &str, chain: Blockchain, light: LightClient) -> Result<Server> {
+        let server = Server::new(port, "", UTXOSet { blockchain: chain })?;
+        server.inner.lock().unwrap().light = Some(light);
+        Ok(server)
+    }
+
+    /// SetMiningThreads sets the number of threads the server mines blocks with
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.miner = Arc::new(Miner::new(threads));
+    }
+
+    /// SetPeerConfig sets the number of inbound and outbound peers the server keeps
+    pub fn set_peer_config(&mut self, config: PeerConfig) {
+        self.peers = Arc::new(PeerManager::new(config));
+    }
+
     pub fn start_server(&self) -> Result<()> {
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
-        };
+        let server1 = self.clone();
         info!(
             "Start server at {}, minning address: {}",
             &self.node_address, &self.mining_address
@@ -110,41 +243,130 @@ impl Server {
 
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height()? == -1 {
-                server1.request_blocks()
-            } else {
-                server1.send_version(KNOWN_NODE1)
-            }
+            // the handshake tells both ends which one has to sync
+            server1.connect(KNOWN_NODE1)
         });
 
+        let server1 = self.clone();
+        thread::spawn(move || server1.maintain_peers());
+
         let listener = TcpListener::bind(&self.node_address).unwrap();
         info!("Server listen...");
 
         for stream in listener.incoming() {
             let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
-            };
-            thread::spawn(move || server1.handle_connection(stream));
+            let reader = stream.try_clone()?;
+            if let Some(id) = self.peers.add_inbound(stream)? {
+                let server1 = self.clone();
+                thread::spawn(move || server1.run_peer(id, reader));
+            }
         }
 
         Ok(())
     }
 
+    /// GetPeerInfo asks the node at addr for the state of its peers
+    ///
+    /// It completes the handshake as a client that serves nothing before asking.
+    pub fn get_peer_info(addr: &str) -> Result<Vec<PeerInfo>> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let version = Versionmsg::new("", 0, -1, rand::random());
+        write_frame(&mut stream, "version", &serialize(&version)?)?;
+        while let Some((cmd, payload)) = read_frame(&mut stream)? {
+            match cmd.as_str() {
+                "version" => write_frame(&mut stream, "verack", &[])?,
+                "verack" => write_frame(&mut stream, "getpeerinfo", &[])?,
+                "peerinfo" => return Ok(deserialize(&payload)?),
+                _ => {}
+            }
+        }
+        Err(format_err!("Node closed the connection"))
+    }
+
+    /// MaintainPeers keeps connecting to the known nodes the server has no connection to
+    ///
+    /// The peer manager holds back addresses that recently failed, so a node that is
+    /// down is retried with backoff. Peers are pinged to tell they are still alive, and
+    /// the ones that do not complete the handshake or answer pings in time are
+    /// disconnected, as are peers banned from the CLI.
+    fn maintain_peers(&self) {
+        loop {
+            thread::sleep(PEER_CHECK_INTERVAL);
+            self.peers.remove_stale(HANDSHAKE_TIMEOUT, PING_TIMEOUT);
+            if let Err(e) = self.peers.remove_banned() {
+                warn!("cannot read the bans: {}", e);
+            }
+            for (id, nonce) in self.peers.start_pings(PING_INTERVAL) {
+                if let Err(e) = self.send_ping(id, "ping", nonce) {
+                    info!("cannot ping peer {}: {}", id, e);
+                    self.peers.remove(id);
+                }
+            }
+            for node in self.get_known_nodes() {
+                if self.peers.find(&node).is_some() {
+                    continue;
+                }
+                if let Err(e) = self.connect(&node) {
+                    warn!("cannot connect to {}: {}", node, e);
+                }
+            }
+        }
+    }
+
+    /// Connect opens an outbound connection to addr, handles its messages and waits
+    /// for the handshake
+    ///
+    /// It returns None if there is no room for another peer, addr is held back after a
+    /// failure or the peer does not complete the handshake.
+    fn connect(&self, addr: &str) -> Result<Option<u64>> {
+        if addr == self.node_address {
+            return Ok(None);
+        }
+        let (id, reader) = match self.peers.connect(addr)? {
+            Some(peer) => peer,
+            None => return Ok(None),
+        };
+        let server1 = self.clone();
+        thread::spawn(move || server1.run_peer(id, reader));
+        if let Err(e) = self.send_version(id) {
+            self.peers.remove(id);
+            return Err(e);
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < HANDSHAKE_TIMEOUT {
+            match self.peers.get_peer(id) {
+                Some(info) if info.handshake => return Ok(Some(id)),
+                Some(_) => thread::sleep(Duration::from_millis(10)),
+                None => return Ok(None),
+            }
+        }
+        info!("peer {} at {} did not complete the handshake", id, addr);
+        self.peers.remove(id);
+        Ok(None)
+    }
+
+    /// RunPeer handles the messages of a peer until its connection is closed
+    fn run_peer(&self, id: u64, stream: TcpStream) {
+        if let Err(e) = self.handle_connection(id, stream) {
+            info!("connection to peer {} failed: {}", id, e);
+        }
+        self.peers.remove(id);
+    }
+
     pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
-        let server = Server::new("7000", "", utxoset)?;
-        server.send_tx(KNOWN_NODE1, tx)?;
+        let mut server = Server::new("7000", "", utxoset)?;
+        // nothing listens on the sending side, so peers get no address to connect back to
+        server.node_address = String::new();
+        if let Some(id) = server.peer_id(KNOWN_NODE1)? {
+            server.send_tx(id, tx)?;
+        }
         Ok(())
     }
 
     /* ------------------- inner halp functions ----------------------------------*/
 
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
-    }
-
     fn add_nodes(&self, addr: &str) {
         self.inner
             .lock()
@@ -158,7 +380,7 @@ impl Server {
     }
 
     fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.get(addr).is_some()
+        self.inner.lock().unwrap().known_nodes.contains(addr)
     }
 
     fn replace_in_transit(&self, hashs: Vec<String>) {
@@ -171,174 +393,327 @@ impl Server {
     }
 
     fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
+        self.inner.lock().unwrap().mempool.get(addr).cloned()
+    }
+
+    fn remove_mempool(&self, txs: &[Transaction]) {
+        let mut inner = self.inner.lock().unwrap();
+        for tx in txs {
+            inner.mempool.remove(&tx.id);
         }
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+    fn get_best_height(&self) -> Result<i32> {
+        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    /// ServesBlocks tells whether a peer advertised the full node service, so it can be
+    /// synced from
+    fn serves_blocks(&self, id: u64) -> bool {
+        self.peers
+            .get_peer(id)
+            .is_some_and(|info| info.services & NODE_NETWORK != 0)
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    fn is_light(&self) -> bool {
+        self.inner.lock().unwrap().light.is_some()
     }
 
-    fn get_best_height(&self) -> Result<i32> {
-        self.inner.lock().unwrap().utxo.blockchain.get_best_height()
+    fn get_locator(&self) -> Result<Vec<String>> {
+        self.inner.lock().unwrap().utxo.blockchain.get_locator()
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
+    fn add_header(&self, header: &BlockHeader) -> Result<bool> {
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .add_header(header)
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
+    fn get_missing_bodies(&self, block_hash: &str) -> Result<Vec<String>> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .get_block(block_hash)
+            .get_missing_bodies(block_hash)
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .verify_transacton(tx)
+            .get_block(block_hash)
     }
 
     fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+        let mut inner = self.inner.lock().unwrap();
+        let update = inner.utxo.add_block(block)?;
+        if !update.connected.is_empty() {
+            // whatever is being mined no longer builds on the tip
+            self.miner.cancel();
+        }
+
+        // transactions of blocks that left the active chain go back to the mempool
+        for block in &update.disconnected {
+            for tx in block.get_transaction() {
+                if !tx.is_coinbase() {
+                    inner.mempool.insert(tx.id.clone(), tx.clone());
+                }
+            }
+        }
+        for block in &update.connected {
+            for tx in block.get_transaction() {
+                inner.mempool.remove(&tx.id);
+            }
+        }
+        match update.invalid {
+            Some(invalid) => Err(invalid.into()),
+            None => Ok(()),
+        }
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    /// NewBlockTemplate builds a block of mempool transactions on top of the tip, along
+    /// with the mining job it belongs to, or returns None if there is nothing to mine
+    ///
+    /// The lock is held throughout, so the transactions, the height the coinbase commits
+    /// to and the job all come from the same tip.
+    fn new_block_template(&self) -> Result<Option<(Block, u64)>> {
+        let mut inner = self.inner.lock().unwrap();
+        let (mut txs, fees) = Server::select_transactions(&mut inner);
+        if txs.is_empty() {
+            return Ok(None);
+        }
+        let height = inner.utxo.blockchain.get_best_height()? + 1;
+        let cbtx =
+            Transaction::new_coinbase(self.mining_address.clone(), String::new(), height, fees)?;
+        txs.insert(0, cbtx);
+        let template = inner.utxo.blockchain.new_block_template(txs)?;
+        Ok(Some((template, self.miner.job())))
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /// MineBlock mines a template and adds the block to the chain
+    ///
+    /// The lock is not held while mining, so other messages are handled meanwhile. It
+    /// returns None if a new tip was accepted before the block was found.
+    fn mine_block(&self, template: Block, job: u64) -> Result<Option<Block>> {
+        let block = match self.miner.mine(template, job)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        self.add_block(block.clone())?;
+        Ok(Some(block))
     }
 
     /* -----------------------------------------------------*/
 
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
-            return Ok(());
+    /// PeerId returns the id of the peer at addr, connecting to it if there is none
+    ///
+    /// It returns None for the address of this node and for addresses that cannot be
+    /// connected to.
+    fn peer_id(&self, addr: &str) -> Result<Option<u64>> {
+        if addr == self.node_address {
+            return Ok(None);
         }
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
-                return Ok(());
-            }
-        };
-
-        stream.write(data)?;
+        match self.peers.find(addr) {
+            Some(id) => Ok(Some(id)),
+            None => self.connect(addr),
+        }
+    }
 
+    /// SendData sends a message to a peer over its connection
+    ///
+    /// A peer whose connection fails is disconnected.
+    fn send_data(&self, id: u64, cmd: &str, payload: &[u8]) -> Result<()> {
+        if let Err(e) = self.peers.send(id, cmd, payload) {
+            info!("cannot send to peer {}: {}", id, e);
+            self.peers.remove(id);
+            return Ok(());
+        }
         info!("data send successfully");
         Ok(())
     }
 
-    fn request_blocks(&self) -> Result<()> {
+    /// RelayInv announces an item to the known nodes, except the peer it came from
+    fn relay_inv(&self, kind: &str, item: &str, from: Option<u64>) -> Result<()> {
         for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
+            match self.peer_id(&node)? {
+                Some(id) if Some(id) != from => self.send_inv(id, kind, vec![item.to_string()])?,
+                _ => {}
+            }
         }
         Ok(())
     }
 
-    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
-        info!("send block data to: {} block hash: {}", addr, b.get_hash());
+    fn send_block(&self, id: u64, b: &Block) -> Result<()> {
+        info!(
+            "send block data to peer {} block hash: {}",
+            id,
+            b.get_hash()
+        );
         let data = Blockmsg {
             addr_from: self.node_address.clone(),
             block: b.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("block"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "block", &serialize(&data)?)
     }
 
-    fn send_addr(&self, addr: &str) -> Result<()> {
-        info!("send address info to: {}", addr);
+    fn send_addr(&self, id: u64) -> Result<()> {
+        info!("send address info to peer {}", id);
         let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "addr", &serialize(&nodes)?)
     }
 
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+    fn send_inv(&self, id: u64, kind: &str, items: Vec<String>) -> Result<()> {
         info!(
-            "send inv message to: {} kind: {} data: {:?}",
-            addr, kind, items
+            "send inv message to peer {} kind: {} data: {:?}",
+            id, kind, items
         );
         let data = Invmsg {
             addr_from: self.node_address.clone(),
             kind: kind.to_string(),
             items,
         };
-        let data = serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "inv", &serialize(&data)?)
+    }
+
+    fn send_get_headers(&self, id: u64) -> Result<()> {
+        info!("send get headers message to peer {}", id);
+        let data = GetHeadersmsg {
+            addr_from: self.node_address.clone(),
+            locator: self.get_locator()?,
+        };
+        self.send_data(id, "getheaders", &serialize(&data)?)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
+    fn send_headers(&self, id: u64, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to peer {}", headers.len(), id);
+        let data = Headersmsg {
             addr_from: self.node_address.clone(),
+            headers,
         };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "headers", &serialize(&data)?)
     }
 
-    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+    fn send_get_data(&self, id: u64, kind: &str, item: &str) -> Result<()> {
         info!(
-            "send get data message to: {} kind: {} id: {}",
-            addr, kind, id
+            "send get data message to peer {} kind: {} id: {}",
+            id, kind, item
         );
         let data = GetDatamsg {
             addr_from: self.node_address.clone(),
             kind: kind.to_string(),
-            id: id.to_string(),
+            id: item.to_string(),
         };
-        let data = serialize(&(cmd_to_bytes("getdata"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "getdata", &serialize(&data)?)
     }
 
-    pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
-        info!("send tx to: {} txid: {}", addr, &tx.id);
-        let data = Txmsg {
+    /// SendGetAddrTxs asks a peer for the transactions of the wallet of a light node, in
+    /// messages of at most MAX_ADDR_TX_KEYS public key hashes
+    fn send_get_addr_txs(&self, id: u64) -> Result<()> {
+        info!("send get addr txs message to peer {}", id);
+        let pub_key_hashes = match &self.inner.lock().unwrap().light {
+            Some(light) => light.get_pub_key_hashes().to_vec(),
+            None => return Ok(()),
+        };
+        for chunk in pub_key_hashes.chunks(MAX_ADDR_TX_KEYS) {
+            let data = GetAddrTxsmsg {
+                addr_from: self.node_address.clone(),
+                pub_key_hashes: chunk.to_vec(),
+            };
+            self.send_data(id, "getaddrtxs", &serialize(&data)?)?;
+        }
+        Ok(())
+    }
+
+    fn send_tx_proof(&self, id: u64, proofs: Vec<TxProof>) -> Result<()> {
+        info!("send {} tx proofs to peer {}", proofs.len(), id);
+        let data = TxProofmsg {
             addr_from: self.node_address.clone(),
-            transaction: tx.clone(),
+            proofs,
         };
-        let data = serialize(&(cmd_to_bytes("tx"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "txproof", &serialize(&data)?)
     }
 
-    fn send_version(&self, addr: &str) -> Result<()> {
-        info!("send version info to: {}", addr);
-        let data = Versionmsg {
+    pub fn send_tx(&self, id: u64, tx: &Transaction) -> Result<()> {
+        info!("send tx to peer {} txid: {}", id, &tx.id);
+        let data = Txmsg {
             addr_from: self.node_address.clone(),
-            best_height: self.get_best_height()?,
-            version: VERSION,
+            transaction: tx.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(id, "tx", &serialize(&data)?)
+    }
+
+    /// SendPing sends a ping, or the pong answering one, to a peer
+    fn send_ping(&self, id: u64, cmd: &str, nonce: u64) -> Result<()> {
+        debug!("send {} {} to peer {}", cmd, nonce, id);
+        self.peers.send(id, cmd, &serialize(&nonce)?)
+    }
+
+    /// SendVersion sends the version of this node to a peer, once per connection
+    fn send_version(&self, id: u64) -> Result<()> {
+        if self.peers.set_version_sent(id) {
+            return Ok(());
+        }
+        info!("send version info to peer {}", id);
+        let services = if self.is_light() { 0 } else { NODE_NETWORK };
+        let data = Versionmsg::new(
+            &self.node_address,
+            services,
+            self.get_best_height()?,
+            self.nonce,
+        );
+        self.peers.send(id, "version", &serialize(&data)?)
     }
 
-    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
+    /// HandleVersion answers the version of a peer with ours and a verack, then syncs
+    /// with the peer if it serves full blocks and has a longer chain
+    ///
+    /// A peer with an incompatible version, that sends its version twice or that is
+    /// this node itself is disconnected.
+    fn handle_version(&self, id: u64, msg: Versionmsg) -> Result<()> {
         info!("receive version msg: {:#?}", msg);
+        if msg.nonce == self.nonce {
+            return Err(format_err!("Peer {} is this node itself", id));
+        }
+        if msg.version < MIN_VERSION {
+            return Err(format_err!(
+                "Peer {} has incompatible version {}",
+                id,
+                msg.version
+            ));
+        }
+        if self
+            .peers
+            .get_peer(id)
+            .is_some_and(|info| info.version.is_some())
+        {
+            return Err(Misbehavior::DuplicateVersion.into());
+        }
+        self.peers.set_version(
+            id,
+            msg.version,
+            msg.services,
+            &msg.user_agent,
+            msg.best_height,
+        );
+        self.send_version(id)?;
+        self.peers.send(id, "verack", &[])?;
+        if msg.addr_from.is_empty() {
+            return Ok(());
+        }
+
         let my_best_height = self.get_best_height()?;
-        if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
-        } else if my_best_height > msg.best_height {
-            self.send_version(&msg.addr_from)?;
+        let full_node = msg.services & NODE_NETWORK != 0;
+        if full_node && (self.is_light() || my_best_height < msg.best_height) {
+            self.send_get_headers(id)?;
         }
 
-        self.send_addr(&msg.addr_from)?;
+        self.send_addr(id)?;
 
         if !self.node_is_known(&msg.addr_from) {
             self.add_nodes(&msg.addr_from);
@@ -355,7 +730,7 @@ impl Server {
         Ok(())
     }
 
-    fn handle_block(&self, msg: Blockmsg) -> Result<()> {
+    fn handle_block(&self, id: u64, msg: Blockmsg) -> Result<()> {
         info!(
             "receive block msg: {}, {}",
             msg.addr_from,
@@ -364,23 +739,50 @@ impl Server {
         self.add_block(msg.block)?;
 
         let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
+        if !in_transit.is_empty() {
             let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+            self.send_get_data(id, "block", block_hash)?;
             in_transit.remove(0);
             self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
         }
 
         Ok(())
     }
 
-    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
+    /// HandleLightHeaders stores the headers of a light node, then asks the peer for the
+    /// transactions of the wallet once the headers have caught up
+    fn handle_light_headers(&self, id: u64, msg: Headersmsg) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            if let Some(light) = &inner.light {
+                light.add_headers(&mut inner.utxo.blockchain, &msg.headers)?;
+            }
+        }
+        if msg.headers.len() == MAX_HEADERS {
+            return self.send_get_headers(id);
+        }
+        self.send_get_addr_txs(id)
+    }
+
+    fn handle_inv(&self, id: u64, msg: Invmsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
+        if msg.kind == "block" && !self.serves_blocks(id) {
+            return Ok(());
+        }
+        if self.is_light() {
+            // a light node follows new blocks through their headers only
+            if msg.kind == "block" {
+                self.send_get_headers(id)?;
+            }
+            return Ok(());
+        }
+        if msg.items.is_empty() {
+            return Err(Misbehavior::EmptyInv.into());
+        }
         if msg.kind == "block" {
             let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+            self.send_get_data(id, "block", block_hash)?;
 
             let mut new_in_transit = Vec::new();
             for b in &msg.items {
@@ -394,180 +796,759 @@ impl Server {
             match self.get_mempool_tx(txid) {
                 Some(tx) => {
                     if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
+                        self.send_get_data(id, "tx", txid)?
                     }
                 }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+                None => self.send_get_data(id, "tx", txid)?,
             }
         }
         Ok(())
     }
 
-    fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
-        info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(&msg.addr_from, "block", block_hashs)?;
+    fn handle_get_headers(&self, id: u64, msg: GetHeadersmsg) -> Result<()> {
+        info!("receive get headers msg: {:#?}", msg);
+        let headers = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_headers_after(&msg.locator, MAX_HEADERS)?;
+        self.send_headers(id, headers)
+    }
+
+    /// HandleHeaders stores the headers, then downloads the bodies of the blocks
+    ///
+    /// A full batch means the peer has more, so headers are requested again first;
+    /// bodies are fetched, parents first, once the headers have caught up. Headers are
+    /// only taken from peers that serve full blocks.
+    fn handle_headers(&self, id: u64, msg: Headersmsg) -> Result<()> {
+        info!(
+            "receive headers msg: {}, {} headers",
+            msg.addr_from,
+            msg.headers.len()
+        );
+        if !self.serves_blocks(id) {
+            return Ok(());
+        }
+        if self.is_light() {
+            return self.handle_light_headers(id, msg);
+        }
+        let last = match msg.headers.last() {
+            Some(header) => header.hash()?,
+            None => return Ok(()),
+        };
+        for header in &msg.headers {
+            self.add_header(header)?;
+        }
+
+        if msg.headers.len() == MAX_HEADERS {
+            return self.send_get_headers(id);
+        }
+        let mut missing = self.get_missing_bodies(&last)?;
+        if !missing.is_empty() {
+            let block_hash = missing.remove(0);
+            self.send_get_data(id, "block", &block_hash)?;
+            self.replace_in_transit(missing);
+        }
         Ok(())
     }
 
-    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
+    fn handle_get_data(&self, id: u64, msg: GetDatamsg) -> Result<()> {
         info!("receive get data msg: {:#?}", msg);
+        let not_found = || Misbehavior::NotFound(msg.id.clone());
         if msg.kind == "block" {
-            let block = self.get_block(&msg.id)?;
-            self.send_block(&msg.addr_from, &block)?;
+            let block = self.get_block(&msg.id).map_err(|_| not_found())?;
+            self.send_block(id, &block)?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+            let tx = self.get_mempool_tx(&msg.id).ok_or_else(not_found)?;
+            self.send_tx(id, &tx)?;
         }
         Ok(())
     }
 
-    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
+    /// HandleGetTxProof replies with proofs for the requested transactions that are
+    /// in the active chain; unknown ones are left out
+    fn handle_get_tx_proof(&self, id: u64, msg: GetTxProofmsg) -> Result<()> {
+        info!("receive get tx proof msg: {:#?}", msg);
+        if msg.txids.len() > MAX_TX_PROOF_IDS {
+            return Err(Misbehavior::TooManyItems(String::from("gettxproof")).into());
+        }
+        let mut proofs = Vec::new();
+        {
+            let inner = self.inner.lock().unwrap();
+            for txid in &msg.txids {
+                if let Ok(proof) = inner.utxo.blockchain.get_tx_proof(txid) {
+                    proofs.push(proof);
+                }
+            }
+        }
+        self.send_tx_proof(id, proofs)
+    }
+
+    fn handle_get_addr_txs(&self, id: u64, msg: GetAddrTxsmsg) -> Result<()> {
+        info!("receive get addr txs msg: {}", msg.addr_from);
+        if msg.pub_key_hashes.len() > MAX_ADDR_TX_KEYS {
+            return Err(Misbehavior::TooManyItems(String::from("getaddrtxs")).into());
+        }
+        let proofs = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_address_proofs(&msg.pub_key_hashes)?;
+        self.send_tx_proof(id, proofs)
+    }
+
+    fn handle_tx_proof(&self, msg: TxProofmsg) -> Result<()> {
+        info!("receive tx proof msg: {}", msg.addr_from);
+        let inner = self.inner.lock().unwrap();
+        if let Some(light) = &inner.light {
+            let mut verified = 0;
+            for proof in &msg.proofs {
+                if light.add_proof(&inner.utxo.blockchain, proof)? {
+                    verified += 1;
+                }
+            }
+            info!("verified {} of {} tx proofs", verified, msg.proofs.len());
+            return Ok(());
+        }
+        for proof in &msg.proofs {
+            let valid = match inner.utxo.blockchain.get_header(&proof.block_hash) {
+                Ok(header) => proof.verify(&header)?,
+                Err(_) => false,
+            };
+            info!(
+                "proof for tx {} in block {}: valid {}",
+                proof.transaction.id, proof.block_hash, valid
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_tx(&self, id: u64, msg: Txmsg) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        if self.is_light() || !self.accept_tx(&msg.transaction)? {
+            return Ok(());
+        }
 
-        let known_nodes = self.get_known_nodes();
         if self.node_address == KNOWN_NODE1 {
-            for node in known_nodes {
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+            self.relay_inv("tx", &msg.transaction.id, Some(id))?;
+        } else if !self.mining_address.is_empty() {
+            self.request_mining();
+        }
+
+        Ok(())
+    }
+
+    /// AcceptTx verifies a transaction from a peer and adds it to the mempool, returning
+    /// false if it is already there or cannot be mined yet
+    ///
+    /// Spending outputs this node does not know only earns a little ban score, as the peer
+    /// may be ahead of it, but a transaction that can never be valid earns as much as an
+    /// invalid block.
+    fn accept_tx(&self, tx: &Transaction) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.mempool.contains_key(&tx.id) {
+            return Ok(false);
+        }
+        let invalid = || Misbehavior::InvalidTransaction(tx.id.clone()).into();
+        if tx.is_coinbase() || !tx.check_id()? {
+            return Err(invalid());
+        }
+        let height = inner.utxo.blockchain.get_best_height()? + 1;
+        let mut spent = HashSet::new();
+        for vin in &tx.vin {
+            if !spent.insert((&vin.txid, vin.vout)) {
+                return Err(invalid());
+            }
+            match inner.utxo.find_output(&vin.txid, vin.vout)? {
+                None => return Err(Misbehavior::MissingInputs(tx.id.clone()).into()),
+                Some(utxo) if !utxo.is_mature_at(height) => {
+                    info!("transaction {} spends immature outputs", tx.id);
+                    return Ok(false);
                 }
+                Some(_) => {}
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
+        }
+        let blockchain = &inner.utxo.blockchain;
+        if !blockchain.verify_transacton(tx)? || blockchain.get_transaction_fee(tx).is_err() {
+            return Err(invalid());
+        }
+        inner.mempool.insert(tx.id.clone(), tx.clone());
+        Ok(true)
+    }
 
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
+    /// RequestMining has the mining thread mine the mempool, starting it the first time
+    ///
+    /// Mining takes a while, so it runs on its own thread while the messages of peers are
+    /// handled. There is only one, so blocks are never mined against each other.
+    fn request_mining(&self) {
+        let mut mine_requests = self.mine_requests.lock().unwrap();
+        let sender = mine_requests.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            let server1 = self.clone();
+            thread::spawn(move || server1.run_miner(receiver));
+            sender
+        });
+        sender.send(()).ok();
+    }
 
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
+    /// RunMiner mines the mempool each time it is requested to
+    ///
+    /// Requests that come in while mining are served by a single pass afterwards.
+    fn run_miner(&self, requests: mpsc::Receiver<()>) {
+        while requests.recv().is_ok() {
+            while requests.try_recv().is_ok() {}
+            if let Err(e) = self.mine_mempool() {
+                warn!("mining failed: {}", e);
+            }
+        }
+    }
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+    /// MineMempool mines blocks with the valid transactions of the mempool until none is left
+    fn mine_mempool(&self) -> Result<()> {
+        while let Some((template, job)) = self.new_block_template()? {
+            let txs = template.get_transaction().clone();
+            let new_block = match self.mine_block(template, job) {
+                Ok(Some(block)) => block,
+                Ok(None) => {
+                    info!("mining cancelled, the tip has changed");
+                    continue;
+                }
+                Err(e) => {
+                    // the transactions made an invalid block, so they are not mined again
+                    self.remove_mempool(&txs);
+                    return Err(e);
+                }
+            };
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+            self.relay_inv("block", &new_block.get_hash(), None)?;
+        }
+        Ok(())
+    }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+    /// SelectTransactions picks the mempool transactions for a block on top of the tip,
+    /// returning them with the fees they pay
+    ///
+    /// A transaction that is not valid on top of the tip is skipped. One that spends an
+    /// output twice, or an output a transaction picked before it spends, is evicted
+    /// from the mempool, so the block never double spends.
+    fn select_transactions(inner: &mut ServerInner) -> (Vec<Transaction>, Amount) {
+        debug!("Current mempool: {:#?}", &inner.mempool);
+        let mut spent = HashSet::new();
+        let mut conflicts = Vec::new();
+        let mut txs = Vec::new();
+        let mut fees = Amount::ZERO;
+
+        for tx in inner.mempool.values() {
+            let mut outpoints = HashSet::new();
+            let conflicting = tx.vin.iter().any(|vin| {
+                let outpoint = (vin.txid.clone(), vin.vout);
+                spent.contains(&outpoint) || !outpoints.insert(outpoint)
+            });
+            if conflicting {
+                conflicts.push(tx.id.clone());
+                continue;
+            }
+            if !matches!(inner.utxo.verify_transaction(tx), Ok(true)) {
+                continue;
+            }
+            let fee = inner.utxo.blockchain.get_transaction_fee(tx);
+            fees = match fee.ok().and_then(|fee| fees.checked_add(fee)) {
+                Some(fees) => fees,
+                None => continue,
+            };
+            spent.extend(outpoints);
+            txs.push(tx.clone());
+        }
 
-                    if mempool.len() == 0 {
-                        break;
+        for txid in conflicts {
+            info!("evict transaction {} from the mempool: double spend", txid);
+            inner.mempool.remove(&txid);
+        }
+        (txs, fees)
+    }
+
+    /// HandleConnection handles the messages of a peer until it closes the connection
+    ///
+    /// A message that breaks the protocol or carries invalid data is dropped and adds
+    /// to the ban score of the peer. A frame that cannot be read drops the connection,
+    /// as the stream can no longer be trusted to be at the start of a frame.
+    fn handle_connection(&self, id: u64, mut stream: TcpStream) -> Result<()> {
+        loop {
+            let (cmd, payload) = match read_frame(&mut stream) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    if let Some(score) = ban_score(&e) {
+                        self.misbehaving(id, score, &e)?;
                     }
+                    return Err(e);
+                }
+            };
+            info!(
+                "Accept request from peer {}: {} length {}",
+                id,
+                cmd,
+                payload.len()
+            );
+            self.peers.touch(id);
+            if let Err(e) = self.handle_message(id, &cmd, &payload) {
+                match ban_score(&e) {
+                    Some(score) => self.misbehaving(id, score, &e)?,
+                    None => return Err(e),
                 }
-                self.clear_mempool();
             }
         }
+    }
 
+    /// Misbehaving adds score to the ban score of a peer for e, failing once the peer
+    /// reaches the ban threshold so its connection is dropped
+    fn misbehaving(&self, id: u64, score: u32, e: &failure::Error) -> Result<()> {
+        warn!("peer {} misbehaving (+{}): {}", id, score, e);
+        if self.peers.misbehaving(id, score, &e.to_string())? {
+            return Err(format_err!("Peer {} reached the ban threshold", id));
+        }
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
-
-        let cmd = bytes_to_cmd(&buffer)?;
+    /// SetPeerAddr records the address a peer says it listens on, so what this node sends
+    /// to that address goes over the connection of the peer
+    ///
+    /// Replies go back over the connection a request came in on whatever the peer says,
+    /// and a peer cannot claim the address of this node, of a known node or of another
+    /// peer, which would divert the messages to that node to it.
+    fn set_peer_addr(&self, id: u64, addr: &str) {
+        match self.peers.get_peer(id) {
+            Some(info) if info.addr != addr => {}
+            _ => return,
+        }
+        if addr == self.node_address || self.node_is_known(addr) || !self.peers.set_addr(id, addr) {
+            info!("peer {} cannot take the address {}", id, addr);
+        }
+    }
 
-        match cmd {
+    /// HandleMessage decodes and handles one message of a peer
+    fn handle_message(&self, id: u64, cmd: &str, payload: &[u8]) -> Result<()> {
+        let msg = bytes_to_cmd(cmd, payload)?;
+        let handshake = self.peers.get_peer(id).is_some_and(|info| info.handshake);
+        if !handshake && !matches!(msg, Message::Version(_) | Message::Verack) {
+            return Err(Misbehavior::BeforeHandshake(cmd.to_string()).into());
+        }
+        if let Some(addr) = msg.addr_from() {
+            self.set_peer_addr(id, addr);
+        }
+        match msg {
             Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+            Message::Inv(data) => self.handle_inv(id, data)?,
+            Message::GetHeaders(data) => self.handle_get_headers(id, data)?,
+            Message::Headers(data) => self.handle_headers(id, data)?,
+            Message::GetTxProof(data) => self.handle_get_tx_proof(id, data)?,
+            Message::TxProof(data) => self.handle_tx_proof(data)?,
+            Message::GetAddrTxs(data) => self.handle_get_addr_txs(id, data)?,
+            Message::GetData(data) => self.handle_get_data(id, data)?,
+            Message::Tx(data) => self.handle_tx(id, data)?,
+            Message::Version(data) => self.handle_version(id, data)?,
+            Message::Verack => self.peers.set_verack(id),
+            Message::Ping(nonce) => self.send_ping(id, "pong", nonce)?,
+            Message::Pong(nonce) => {
+                if !self.peers.set_pong(id, nonce) {
+                    debug!("unexpected pong {} from peer {}", nonce, id);
+                }
+            }
+            Message::Block(data) => {
+                self.peers.set_best_height(id, data.block.get_height());
+                self.handle_block(id, data)?
+            }
+            Message::GetPeerInfo => {
+                // the peers of a node are only told to the CLI on the same machine
+                if !self.peers.is_local(id) {
+                    return Err(Misbehavior::UnknownCommand(String::from("getpeerinfo")).into());
+                }
+                let infos = self.peers.get_peer_info();
+                self.peers.send(id, "peerinfo", &serialize(&infos)?)?
+            }
         }
-
         Ok(())
     }
 }
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
+/// BanScore returns the ban score a peer earns for a message failing with e, or None
+/// if the failure is not the fault of the peer
+///
+/// A block whose parent is unknown or that does not extend the tip may just come out
+/// of order, and one from the future may just come from a peer whose clock is ahead,
+/// so they earn nothing.
+fn ban_score(e: &failure::Error) -> Option<u32> {
+    if e.downcast_ref::<WireError>().is_some() {
+        return Some(20);
     }
-    data
-}
-
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
+    if let Some(misbehavior) = e.downcast_ref::<Misbehavior>() {
+        return match misbehavior {
+            Misbehavior::InvalidTransaction(_) => Some(100),
+            Misbehavior::BadPayload(_) | Misbehavior::TooManyItems(_) => Some(20),
+            _ => Some(10),
+        };
     }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
+    match e.downcast_ref::<BlockError>()? {
+        BlockError::UnknownParent | BlockError::NotOnTip | BlockError::TimeTooNew => Some(0),
+        _ => Some(100),
+    }
+}
 
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
+/// BytesToCmd decodes the payload of a frame into the message its command names
+fn bytes_to_cmd(cmd: &str, data: &[u8]) -> Result<Message> {
+    if cmd == "addr" {
+        let data: Vec<String> = decode(cmd, data)?;
         Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
+    } else if cmd == "block" {
+        let data: Blockmsg = decode(cmd, data)?;
         Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data: Invmsg = deserialize(data)?;
+    } else if cmd == "gettxproof" {
+        let data: GetTxProofmsg = decode(cmd, data)?;
+        Ok(Message::GetTxProof(data))
+    } else if cmd == "txproof" {
+        let data: TxProofmsg = decode(cmd, data)?;
+        Ok(Message::TxProof(data))
+    } else if cmd == "getaddrtxs" {
+        let data: GetAddrTxsmsg = decode(cmd, data)?;
+        Ok(Message::GetAddrTxs(data))
+    } else if cmd == "inv" {
+        let data: Invmsg = decode(cmd, data)?;
         Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDatamsg = deserialize(data)?;
+    } else if cmd == "getheaders" {
+        let data: GetHeadersmsg = decode(cmd, data)?;
+        Ok(Message::GetHeaders(data))
+    } else if cmd == "headers" {
+        let data: Headersmsg = decode(cmd, data)?;
+        Ok(Message::Headers(data))
+    } else if cmd == "getdata" {
+        let data: GetDatamsg = decode(cmd, data)?;
         Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data: Txmsg = deserialize(data)?;
+    } else if cmd == "tx" {
+        let data: Txmsg = decode(cmd, data)?;
         Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data: Versionmsg = deserialize(data)?;
+    } else if cmd == "version" {
+        let data: Versionmsg = decode(cmd, data)?;
         Ok(Message::Version(data))
+    } else if cmd == "verack" {
+        Ok(Message::Verack)
+    } else if cmd == "ping" {
+        Ok(Message::Ping(decode(cmd, data)?))
+    } else if cmd == "pong" {
+        Ok(Message::Pong(decode(cmd, data)?))
+    } else if cmd == "getpeerinfo" {
+        Ok(Message::GetPeerInfo)
     } else {
-        Err(format_err!("Unknown command in the server"))
+        Err(Misbehavior::UnknownCommand(cmd.to_string()).into())
     }
 }
 
+/// Decode decodes the payload of a cmd message
+fn decode<'a, T: Deserialize<'a>>(cmd: &str, data: &'a [u8]) -> Result<T> {
+    deserialize(data).map_err(|_| Misbehavior::BadPayload(cmd.to_string()).into())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::blockchain::*;
+    use crate::store::MemoryStore;
     use crate::wallet::*;
 
     #[test]
     fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create_blockchain(wa1).unwrap();
+        let wa1 = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(wa1, store).unwrap();
         let utxo_set = UTXOSet { blockchain: bc };
         let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
 
-        let vmsg = Versionmsg {
-            addr_from: server.node_address.clone(),
-            best_height: server.get_best_height().unwrap(),
-            version: VERSION,
-        };
-        let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
-        if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
+        let vmsg = Versionmsg::new(
+            &server.node_address,
+            NODE_NETWORK,
+            server.get_best_height().unwrap(),
+            server.nonce,
+        );
+        let data = serialize(&vmsg).unwrap();
+        if let Message::Version(v) = bytes_to_cmd("version", &data).unwrap() {
             assert_eq!(v, vmsg);
         } else {
             panic!("wrong!");
         }
+        let misbehavior = |e: failure::Error| e.downcast::<Misbehavior>().unwrap();
+        assert_eq!(
+            misbehavior(bytes_to_cmd("unknown", &data).unwrap_err()),
+            Misbehavior::UnknownCommand(String::from("unknown"))
+        );
+        assert_eq!(
+            misbehavior(bytes_to_cmd("version", &data[..2]).unwrap_err()),
+            Misbehavior::BadPayload(String::from("version"))
+        );
+    }
+
+    #[test]
+    fn test_select_transactions() {
+        let wallet = Wallet::new();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(wallet.get_address(), store).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        let pay = |to: &str| {
+            let amount = Amount::from_coins(1);
+            Transaction::new_UTXO(&wallet, to, amount, Amount::ZERO, &utxo_set).unwrap()
+        };
+        // both spend the genesis coinbase, and the last spends it twice on its own
+        let a = pay(&Wallet::new().get_address());
+        let b = pay(&Wallet::new().get_address());
+        let mut twice = a.clone();
+        twice.vin.push(twice.vin[0].clone());
+        twice.id = String::from("twice");
+        let server = Server::new("7879", "", utxo_set).unwrap();
+        for tx in [a, b, twice] {
+            server
+                .inner
+                .lock()
+                .unwrap()
+                .mempool
+                .insert(tx.id.clone(), tx);
+        }
+
+        let (txs, fees) = Server::select_transactions(&mut server.inner.lock().unwrap());
+        assert_eq!(txs.len(), 1);
+        assert_eq!(fees, Amount::ZERO);
+        let mempool = server.inner.lock().unwrap().mempool.clone();
+        assert_eq!(mempool.keys().collect::<Vec<_>>(), vec![&txs[0].id]);
+    }
+
+    #[test]
+    fn test_accept_tx() {
+        let wallet = Wallet::new();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(wallet.get_address(), store).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        let amount = Amount::from_coins(1);
+        let to = Wallet::new().get_address();
+        let tx = Transaction::new_UTXO(&wallet, &to, amount, Amount::ZERO, &utxo_set).unwrap();
+        // spends the genesis coinbase of another chain
+        let other = Wallet::new();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(other.get_address(), store).unwrap();
+        let other_set = UTXOSet::new(bc).unwrap();
+        let unknown = Transaction::new_UTXO(&other, &to, amount, Amount::ZERO, &other_set).unwrap();
+        let mut forged = tx.clone();
+        forged.vin[0].signature[0] ^= 1;
+        let server = Server::new("7880", "", utxo_set).unwrap();
+
+        let score = |e: failure::Error| {
+            let score = ban_score(&e);
+            (e.downcast::<Misbehavior>().unwrap(), score)
+        };
+        assert_eq!(
+            score(server.accept_tx(&forged).unwrap_err()),
+            (
+                Misbehavior::InvalidTransaction(forged.id.clone()),
+                Some(100)
+            )
+        );
+        assert_eq!(
+            score(server.accept_tx(&unknown).unwrap_err()),
+            (Misbehavior::MissingInputs(unknown.id.clone()), Some(10))
+        );
+        assert!(server.get_mempool_tx(&tx.id).is_none());
+        assert!(server.accept_tx(&tx).unwrap());
+        assert!(!server.accept_tx(&tx).unwrap());
+        assert!(server.get_mempool_tx(&tx.id).is_some());
+    }
+
+    /// Accept connects to server as an inbound peer
+    fn accept(server: &Server, listener: &TcpListener) -> TcpStream {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let reader = stream.try_clone().unwrap();
+        let id = server.peers.add_inbound(stream).unwrap().unwrap();
+        let server1 = server.clone();
+        thread::spawn(move || server1.run_peer(id, reader));
+        client.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+        client
+    }
+
+    fn is_closed(stream: &mut TcpStream) -> bool {
+        !matches!(read_frame(stream), Ok(Some(_)))
+    }
+
+    /// GetPeerInfo asks server for its peers over the connection of a client
+    fn get_peer_info(client: &mut TcpStream) -> Vec<PeerInfo> {
+        write_frame(client, "getpeerinfo", &[]).unwrap();
+        let (cmd, payload) = read_frame(client).unwrap().unwrap();
+        assert_eq!(cmd, "peerinfo");
+        deserialize(&payload).unwrap()
+    }
+
+    fn test_server(port: &str) -> Server {
+        let wa1 = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(wa1, store).unwrap();
+        Server::new(port, "", UTXOSet { blockchain: bc }).unwrap()
+    }
+
+    #[test]
+    fn test_handshake() {
+        let server = test_server("7879");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // a node does not connect to itself
+        let mut client = accept(&server, &listener);
+        let version = Versionmsg::new("", 0, -1, server.nonce);
+        write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+        assert!(is_closed(&mut client));
+
+        let mut client = accept(&server, &listener);
+        let mut version = Versionmsg::new("", 0, -1, 7);
+        version.version = MIN_VERSION - 1;
+        write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+        assert!(is_closed(&mut client));
+
+        // nothing but the handshake is accepted before it completes
+        let mut client = accept(&server, &listener);
+        write_frame(&mut client, "getpeerinfo", &[]).unwrap();
+        let version = Versionmsg::new("", 0, -1, 7);
+        write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+        let (cmd, payload) = read_frame(&mut client).unwrap().unwrap();
+        let theirs: Versionmsg = deserialize(&payload).unwrap();
+        assert_eq!((cmd.as_str(), theirs.nonce), ("version", server.nonce));
+        assert_eq!((theirs.services, theirs.best_height), (NODE_NETWORK, 0));
+        assert_eq!(read_frame(&mut client).unwrap().unwrap().0, "verack");
+        write_frame(&mut client, "verack", &[]).unwrap();
+        let infos = get_peer_info(&mut client);
+        assert_eq!(infos.len(), 1);
+        assert!(infos[0].handshake);
+        assert_eq!(infos[0].user_agent, USER_AGENT);
+        assert_eq!(infos[0].ban_score, 10);
+
+        write_frame(&mut client, "ping", &serialize(&5u64).unwrap()).unwrap();
+        let (cmd, payload) = read_frame(&mut client).unwrap().unwrap();
+        assert_eq!(
+            (cmd.as_str(), deserialize::<u64>(&payload).unwrap()),
+            ("pong", 5)
+        );
+    }
+
+    #[test]
+    fn test_ban() {
+        let mut server = test_server("7880");
+        server.set_peer_config(PeerConfig {
+            ban_threshold: 50,
+            ban_local: true,
+            ..PeerConfig::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut client = accept(&server, &listener);
+        let version = Versionmsg::new("", 0, -1, 7);
+        write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+        write_frame(&mut client, "verack", &[]).unwrap();
+        let getdata = GetDatamsg {
+            addr_from: String::new(),
+            kind: String::from("tx"),
+            id: String::from("missing"),
+        };
+        write_frame(&mut client, "getdata", &serialize(&getdata).unwrap()).unwrap();
+        write_frame(&mut client, "unknown", &[]).unwrap();
+        write_frame(&mut client, "ping", &[1]).unwrap();
+        assert_eq!(read_frame(&mut client).unwrap().unwrap().0, "version");
+        assert_eq!(read_frame(&mut client).unwrap().unwrap().0, "verack");
+        // misbehaving peers stay connected until they reach the threshold
+        assert_eq!(get_peer_info(&mut client)[0].ban_score, 40);
+
+        // a malformed getheaders crosses it: the address is banned
+        write_frame(&mut client, "getheaders", &[]).unwrap();
+        assert!(is_closed(&mut client));
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(server.peers.add_inbound(stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_forged_addr() {
+        let server = test_server("7882");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handshake = |addr_from: &str| {
+            let mut client = accept(&server, &listener);
+            let version = Versionmsg::new(addr_from, NODE_NETWORK, 5, 7);
+            write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+            write_frame(&mut client, "verack", &[]).unwrap();
+            // replies go back over the connection, as a peer with a longer chain is
+            // asked for its headers
+            for cmd in ["version", "verack", "getheaders", "addr"] {
+                assert_eq!(read_frame(&mut client).unwrap().unwrap().0, cmd);
+            }
+            client
+        };
+        let count = |addr: &str| {
+            let infos = server.peers.get_peer_info();
+            infos.iter().filter(|info| info.addr == addr).count()
+        };
+
+        // a peer cannot take the address of a known node
+        let _client = handshake(KNOWN_NODE1);
+        assert_eq!(count(KNOWN_NODE1), 0);
+
+        // nor that of another peer
+        let _first = handshake("127.0.0.1:9");
+        let _second = handshake("127.0.0.1:9");
+        assert_eq!(count("127.0.0.1:9"), 1);
+    }
+
+    #[test]
+    fn test_light_peer() {
+        let server = test_server("7883");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = accept(&server, &listener);
+        let version = Versionmsg::new("127.0.0.1:9", 0, 5, 7);
+        write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+        write_frame(&mut client, "verack", &[]).unwrap();
+        // a light node has a longer chain of headers, but no blocks to sync from
+        for cmd in ["version", "verack", "addr"] {
+            assert_eq!(read_frame(&mut client).unwrap().unwrap().0, cmd);
+        }
+        assert_eq!(server.peers.get_peer_info()[0].services, 0);
+
+        let inv = Invmsg {
+            addr_from: String::from("127.0.0.1:9"),
+            kind: String::from("block"),
+            items: vec![String::from("unknown")],
+        };
+        write_frame(&mut client, "inv", &serialize(&inv).unwrap()).unwrap();
+        let infos = get_peer_info(&mut client);
+        assert_eq!(infos[0].ban_score, 0);
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let server = test_server("7881");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = accept(&server, &listener);
+        let version = Versionmsg::new("", 0, -1, 7);
+        write_frame(&mut client, "version", &serialize(&version).unwrap()).unwrap();
+        write_frame(&mut client, "verack", &[]).unwrap();
+        assert_eq!(read_frame(&mut client).unwrap().unwrap().0, "version");
+        assert_eq!(read_frame(&mut client).unwrap().unwrap().0, "verack");
+
+        let getaddrtxs = GetAddrTxsmsg {
+            addr_from: String::new(),
+            pub_key_hashes: vec![vec![0; 20]; MAX_ADDR_TX_KEYS + 1],
+        };
+        let data = serialize(&getaddrtxs).unwrap();
+        write_frame(&mut client, "getaddrtxs", &data).unwrap();
+        assert_eq!(get_peer_info(&mut client)[0].ban_score, 20);
+
+        let gettxproof = GetTxProofmsg {
+            addr_from: String::new(),
+            txids: vec![String::new(); MAX_TX_PROOF_IDS + 1],
+        };
+        let data = serialize(&gettxproof).unwrap();
+        write_frame(&mut client, "gettxproof", &data).unwrap();
+        assert_eq!(get_peer_info(&mut client)[0].ban_score, 40);
     }
 }
@@ -0,0 +1,132 @@
+//! SPV light client
+//!
+//! A light client keeps only block headers, checked for proof of work and difficulty
+//! like a full node does, and asks full nodes for merkle proofs of the transactions
+//! touching its addresses. Its balance is computed from the transactions whose proof
+//! checks out against a header of its best chain.
+
+use crate::amount::Amount;
+use crate::block::{BlockHeader, TxProof};
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::store::Column;
+use crate::transaction::Transaction;
+use crate::utxoset::UTXO;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use log::info;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// LIGHT_DB is the db in the data directory where a light client keeps its headers
+/// and verified transactions
+pub const LIGHT_DB: &str = "spv";
+
+/// LightClient keeps the verified transactions of a set of addresses
+///
+/// The headers live in a Blockchain that has no block bodies; its tip is the header
+/// with the most work.
+///
+/// Proofs are kept in the store of the header chain.
+pub struct LightClient {
+    pub_key_hashes: Vec<Vec<u8>>,
+}
+
+impl LightClient {
+    /// Open opens the header chain and the verified transactions of a light client
+    pub fn open(datadir: &Path, pub_key_hashes: Vec<Vec<u8>>) -> Result<(Blockchain, LightClient)> {
+        let chain = Blockchain::open(datadir, LIGHT_DB)?;
+        Ok((chain, LightClient { pub_key_hashes }))
+    }
+
+    pub fn get_pub_key_hashes(&self) -> &[Vec<u8>] {
+        &self.pub_key_hashes
+    }
+
+    /// AddHeaders stores valid headers and moves the tip to the one with the most work
+    pub fn add_headers(&self, chain: &mut Blockchain, headers: &[BlockHeader]) -> Result<()> {
+        for header in headers {
+            if !chain.add_header(header)? {
+                continue;
+            }
+            let hash = header.hash()?;
+            if chain.tip.is_empty()
+                || chain.get_chain_work(&hash)? > chain.get_chain_work(&chain.tip)?
+            {
+                chain.set_tip(&hash)?;
+            }
+        }
+        info!(
+            "light client synced headers to height {}",
+            chain.get_best_height()?
+        );
+        Ok(())
+    }
+
+    /// AddProof keeps the transaction of a proof that checks against a known header
+    ///
+    /// It returns false, keeping nothing, if the proof is invalid or its block unknown.
+    pub fn add_proof(&self, chain: &Blockchain, proof: &TxProof) -> Result<bool> {
+        let header = match chain.get_header(&proof.block_hash) {
+            Ok(header) => header,
+            Err(_) => return Ok(false),
+        };
+        if !proof.verify(&header)? {
+            return Ok(false);
+        }
+        chain.get_store().insert(
+            Column::Proofs,
+            proof.transaction.id.as_bytes(),
+            serialize(proof)?,
+        )?;
+        Ok(true)
+    }
+
+    /// GetBalance returns the mature and immature balance of a public key hash
+    ///
+    /// Only transactions in blocks of the best header chain count, so proofs for
+    /// blocks that were reorganized away are ignored.
+    pub fn get_balance(&self, chain: &Blockchain, pub_key_hash: &[u8]) -> Result<(Amount, Amount)> {
+        let best_height = chain.get_best_height()?;
+        let mut txs: Vec<(Transaction, i32)> = Vec::new();
+        for kv in chain.get_store().iter(Column::Proofs) {
+            let (_, v) = kv?;
+            let proof: TxProof = deserialize(&v)?;
+            let confirmations = chain.get_confirmations(&proof.block_hash)?;
+            if confirmations > 0 {
+                txs.push((proof.transaction, best_height - confirmations + 1));
+            }
+        }
+
+        let spent: HashSet<(String, i32)> = txs
+            .iter()
+            .filter(|(tx, _)| !tx.is_coinbase())
+            .flat_map(|(tx, _)| tx.vin.iter().map(|vin| (vin.txid.clone(), vin.vout)))
+            .collect();
+        let mut mature = Amount::ZERO;
+        let mut immature = Amount::ZERO;
+        for (tx, height) in &txs {
+            for (vout, output) in tx.vout.iter().enumerate() {
+                if !output.is_locked_with_key(pub_key_hash)
+                    || spent.contains(&(tx.id.clone(), vout as i32))
+                {
+                    continue;
+                }
+                let utxo = UTXO {
+                    output: output.clone(),
+                    is_coinbase: tx.is_coinbase(),
+                    height: *height,
+                };
+                let total = if utxo.is_mature_at(best_height + 1) {
+                    &mut mature
+                } else {
+                    &mut immature
+                };
+                *total = total
+                    .checked_add(output.value)
+                    .ok_or_else(|| format_err!("Balance exceeds the maximum amount"))?;
+            }
+        }
+        Ok((mature, immature))
+    }
+}
@@ -0,0 +1,625 @@
+//! storage backends
+//!
+//! A Store is a key-value store split into columns. Blocks, their index and undo data
+//! are read and written through ChainStore, the UTXO set through UtxoStore; both are
+//! implemented for every Store. SledStore keeps the columns on disk and is what nodes
+//! use, MemoryStore keeps them in memory for tests and simulations.
+//!
+//! Writes that have to land together, like connecting a block, are collected in a
+//! Batch and committed in one atomic write.
+
+use crate::block::BlockHeader;
+use crate::blockchain::{BlockIndex, TxLocation};
+use crate::errors::Result;
+use crate::transaction::Transaction;
+use crate::utxoset::{AddressEvent, BlockUndo, UTXO};
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled;
+use sled::Transactional;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// TIP_KEY is the key of the hash of the active chain's tip in Column::Meta
+const TIP_KEY: &str = "LAST";
+/// TXINDEX_KEY marks in Column::Meta that the transaction index is kept
+const TXINDEX_KEY: &str = "TXINDEX";
+/// ADDRINDEX_KEY marks in Column::Meta that the address index is kept
+const ADDRINDEX_KEY: &str = "ADDRINDEX";
+/// UTXO_TIP_KEY is the key of the hash of the block the UTXO set is at in Column::Meta
+///
+/// It changed when the UTXO set went from one entry per transaction to one per output,
+/// so a UTXO set kept the old way has no tip and is rebuilt.
+const UTXO_TIP_KEY: &str = "OUTPOINT_TIP";
+
+/// Column is a group of keys of a Store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Column {
+    Meta,
+    Bodies,
+    Headers,
+    Index,
+    Undo,
+    Utxos,
+    Proofs,
+    Heights,
+    TxIndex,
+    AddrIndex,
+}
+
+impl Column {
+    pub const ALL: [Column; 10] = [
+        Column::Meta,
+        Column::Bodies,
+        Column::Headers,
+        Column::Index,
+        Column::Undo,
+        Column::Utxos,
+        Column::Proofs,
+        Column::Heights,
+        Column::TxIndex,
+        Column::AddrIndex,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Meta => "meta",
+            Column::Bodies => "bodies",
+            Column::Headers => "headers",
+            Column::Index => "index",
+            Column::Undo => "undo",
+            Column::Utxos => "outpoints",
+            Column::Proofs => "proofs",
+            Column::Heights => "heights",
+            Column::TxIndex => "txindex",
+            Column::AddrIndex => "addrindex",
+        }
+    }
+}
+
+/// StoreIter iterates over the keys and values of a column in key order
+pub type StoreIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Change sets a key of a column to a value, or removes it if there is none
+pub type Change = (Column, Vec<u8>, Option<Vec<u8>>);
+
+/// Store is a key-value storage backend
+pub trait Store: Send + Sync {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn insert(&self, column: Column, key: &[u8], value: Vec<u8>) -> Result<()>;
+    fn remove(&self, column: Column, key: &[u8]) -> Result<()>;
+    fn iter(&self, column: Column) -> StoreIter<'_>;
+    /// Clear removes every key of the column
+    fn clear(&self, column: Column) -> Result<()>;
+    /// Write applies the changes atomically and durably: after a crash either all
+    /// of them are stored or none is
+    fn write(&self, changes: Vec<Change>) -> Result<()>;
+    /// Flush makes sure every write so far is durable
+    fn flush(&self) -> Result<()>;
+}
+
+fn get_value<T: DeserializeOwned, S: Store + ?Sized>(
+    store: &S,
+    column: Column,
+    key: &[u8],
+) -> Result<Option<T>> {
+    match store.get(column, key)? {
+        Some(data) => Ok(Some(deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+fn put_value<T: Serialize, S: Store + ?Sized>(
+    store: &S,
+    column: Column,
+    key: &[u8],
+    value: &T,
+) -> Result<()> {
+    store.insert(column, key, serialize(value)?)
+}
+
+/// height_key encodes a height so keys sort by height
+fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
+}
+
+/// OutpointKey is the txid followed by the big-endian vout, so the outputs of a
+/// transaction are next to each other and in order
+fn outpoint_key(txid: &str, vout: i32) -> Vec<u8> {
+    let mut key = txid.as_bytes().to_vec();
+    key.extend_from_slice(&(vout as u32).to_be_bytes());
+    key
+}
+
+fn parse_outpoint_key(key: &[u8]) -> Result<(String, i32)> {
+    if key.len() < 4 {
+        return Err(format_err!("ERROR: Bad outpoint key"));
+    }
+    let (txid, vout) = key.split_at(key.len() - 4);
+    let vout = u32::from_be_bytes([vout[0], vout[1], vout[2], vout[3]]);
+    Ok((String::from_utf8(txid.to_vec())?, vout as i32))
+}
+
+/// ChainStore keeps the headers, bodies, index and undo data of blocks, keyed by hash,
+/// the hashes of the active chain, keyed by height, and where its transactions are
+pub trait ChainStore: Store {
+    /// GetTip returns the hash of the active chain's tip, empty if there is none
+    fn get_tip(&self) -> Result<String> {
+        match self.get(Column::Meta, TIP_KEY.as_bytes())? {
+            Some(hash) => Ok(String::from_utf8(hash)?),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn put_tip(&self, block_hash: &str) -> Result<()> {
+        self.insert(
+            Column::Meta,
+            TIP_KEY.as_bytes(),
+            block_hash.as_bytes().to_vec(),
+        )
+    }
+
+    fn get_header(&self, block_hash: &str) -> Result<Option<BlockHeader>> {
+        get_value(self, Column::Headers, block_hash.as_bytes())
+    }
+
+    fn put_header(&self, block_hash: &str, header: &BlockHeader) -> Result<()> {
+        put_value(self, Column::Headers, block_hash.as_bytes(), header)
+    }
+
+    fn get_body(&self, block_hash: &str) -> Result<Option<Vec<Transaction>>> {
+        get_value(self, Column::Bodies, block_hash.as_bytes())
+    }
+
+    fn put_body(&self, block_hash: &str, transactions: &[Transaction]) -> Result<()> {
+        put_value(self, Column::Bodies, block_hash.as_bytes(), &transactions)
+    }
+
+    fn get_block_index(&self, block_hash: &str) -> Result<Option<BlockIndex>> {
+        get_value(self, Column::Index, block_hash.as_bytes())
+    }
+
+    fn put_block_index(&self, block_hash: &str, index: &BlockIndex) -> Result<()> {
+        put_value(self, Column::Index, block_hash.as_bytes(), index)
+    }
+
+    fn get_undo(&self, block_hash: &str) -> Result<Option<BlockUndo>> {
+        get_value(self, Column::Undo, block_hash.as_bytes())
+    }
+
+    fn put_undo(&self, block_hash: &str, undo: &BlockUndo) -> Result<()> {
+        put_value(self, Column::Undo, block_hash.as_bytes(), undo)
+    }
+
+    fn remove_undo(&self, block_hash: &str) -> Result<()> {
+        self.remove(Column::Undo, block_hash.as_bytes())
+    }
+
+    /// GetHashByHeight returns the hash of the block of the active chain at height
+    fn get_hash_by_height(&self, height: i32) -> Result<Option<String>> {
+        match self.get(Column::Heights, &height_key(height))? {
+            Some(hash) => Ok(Some(String::from_utf8(hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_hash_by_height(&self, height: i32, block_hash: &str) -> Result<()> {
+        self.insert(
+            Column::Heights,
+            &height_key(height),
+            block_hash.as_bytes().to_vec(),
+        )
+    }
+
+    fn remove_hash_by_height(&self, height: i32) -> Result<()> {
+        self.remove(Column::Heights, &height_key(height))
+    }
+
+    /// HasTxIndex checks whether the transaction index is kept
+    fn has_txindex(&self) -> Result<bool> {
+        Ok(self.get(Column::Meta, TXINDEX_KEY.as_bytes())?.is_some())
+    }
+
+    fn put_txindex(&self) -> Result<()> {
+        self.insert(Column::Meta, TXINDEX_KEY.as_bytes(), Vec::new())
+    }
+
+    fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        get_value(self, Column::TxIndex, txid.as_bytes())
+    }
+
+    fn put_tx_location(&self, txid: &str, location: &TxLocation) -> Result<()> {
+        put_value(self, Column::TxIndex, txid.as_bytes(), location)
+    }
+
+    fn remove_tx_location(&self, txid: &str) -> Result<()> {
+        self.remove(Column::TxIndex, txid.as_bytes())
+    }
+}
+
+impl<S: Store + ?Sized> ChainStore for S {}
+
+/// UtxoStore keeps the unspent outputs, keyed by outpoint (txid, vout), and the
+/// outputs funding and spent by each address, keyed by public key hash
+pub trait UtxoStore: Store {
+    fn get_utxo(&self, txid: &str, vout: i32) -> Result<Option<UTXO>> {
+        get_value(self, Column::Utxos, &outpoint_key(txid, vout))
+    }
+
+    fn put_utxo(&self, txid: &str, vout: i32, utxo: &UTXO) -> Result<()> {
+        put_value(self, Column::Utxos, &outpoint_key(txid, vout), utxo)
+    }
+
+    fn remove_utxo(&self, txid: &str, vout: i32) -> Result<()> {
+        self.remove(Column::Utxos, &outpoint_key(txid, vout))
+    }
+
+    /// GetUtxoTip returns the hash of the last block applied to the UTXO set, empty
+    /// if there is none
+    fn get_utxo_tip(&self) -> Result<String> {
+        match self.get(Column::Meta, UTXO_TIP_KEY.as_bytes())? {
+            Some(hash) => Ok(String::from_utf8(hash)?),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn put_utxo_tip(&self, block_hash: &str) -> Result<()> {
+        self.insert(
+            Column::Meta,
+            UTXO_TIP_KEY.as_bytes(),
+            block_hash.as_bytes().to_vec(),
+        )
+    }
+
+    /// HasAddrIndex checks whether the address index is kept
+    fn has_addrindex(&self) -> Result<bool> {
+        Ok(self.get(Column::Meta, ADDRINDEX_KEY.as_bytes())?.is_some())
+    }
+
+    fn put_addrindex(&self) -> Result<()> {
+        self.insert(Column::Meta, ADDRINDEX_KEY.as_bytes(), Vec::new())
+    }
+
+    /// GetAddressEvents returns the events of an address, oldest first
+    fn get_address_events(&self, pub_key_hash: &[u8]) -> Result<Vec<AddressEvent>> {
+        Ok(get_value(self, Column::AddrIndex, pub_key_hash)?.unwrap_or_default())
+    }
+
+    fn put_address_events(&self, pub_key_hash: &[u8], events: &[AddressEvent]) -> Result<()> {
+        if events.is_empty() {
+            return self.remove(Column::AddrIndex, pub_key_hash);
+        }
+        put_value(self, Column::AddrIndex, pub_key_hash, &events)
+    }
+
+    /// IterUtxos returns every unspent output with its txid and vout
+    fn iter_utxos(&self) -> Box<dyn Iterator<Item = Result<(String, i32, UTXO)>> + '_> {
+        Box::new(self.iter(Column::Utxos).map(|kv| {
+            let (k, v) = kv?;
+            let (txid, vout) = parse_outpoint_key(&k)?;
+            Ok((txid, vout, deserialize(&v)?))
+        }))
+    }
+}
+
+impl<S: Store + ?Sized> UtxoStore for S {}
+
+/// SledStore keeps each column in a tree of a sled db
+pub struct SledStore {
+    db: sled::Db,
+    trees: Vec<sled::Tree>,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> Result<SledStore> {
+        let db = sled::open(path)?;
+        let mut trees = Vec::new();
+        for column in Column::ALL {
+            trees.push(db.open_tree(column.name())?);
+        }
+        Ok(SledStore { db, trees })
+    }
+
+    fn tree(&self, column: Column) -> &sled::Tree {
+        &self.trees[column as usize]
+    }
+}
+
+impl Store for SledStore {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree(column).get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, column: Column, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.tree(column).insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, column: Column, key: &[u8]) -> Result<()> {
+        self.tree(column).remove(key)?;
+        Ok(())
+    }
+
+    fn iter(&self, column: Column) -> StoreIter<'_> {
+        Box::new(self.tree(column).iter().map(|kv| {
+            let (k, v) = kv?;
+            Ok((k.to_vec(), v.to_vec()))
+        }))
+    }
+
+    fn clear(&self, column: Column) -> Result<()> {
+        self.tree(column).clear()?;
+        Ok(())
+    }
+
+    fn write(&self, changes: Vec<Change>) -> Result<()> {
+        self.trees
+            .as_slice()
+            .transaction(|trees| {
+                for (column, key, value) in &changes {
+                    let tree = &trees[*column as usize];
+                    match value {
+                        Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                        None => tree.remove(key.as_slice())?,
+                    };
+                }
+                Ok(())
+            })
+            .map_err(|e: sled::transaction::TransactionError| {
+                format_err!("ERROR: Write failed: {:?}", e)
+            })?;
+        self.flush()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+type Columns = HashMap<Column, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+/// MemoryStore keeps the columns in memory, losing them when dropped
+#[derive(Default)]
+pub struct MemoryStore {
+    columns: Mutex<Columns>,
+}
+
+impl Store for MemoryStore {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let columns = self.columns.lock().unwrap();
+        Ok(columns.get(&column).and_then(|c| c.get(key)).cloned())
+    }
+
+    fn insert(&self, column: Column, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let mut columns = self.columns.lock().unwrap();
+        columns
+            .entry(column)
+            .or_default()
+            .insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn remove(&self, column: Column, key: &[u8]) -> Result<()> {
+        let mut columns = self.columns.lock().unwrap();
+        if let Some(c) = columns.get_mut(&column) {
+            c.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Iter walks a snapshot of the column, so writes during iteration are not seen
+    fn iter(&self, column: Column) -> StoreIter<'_> {
+        let columns = self.columns.lock().unwrap();
+        let kvs: Vec<(Vec<u8>, Vec<u8>)> = match columns.get(&column) {
+            Some(c) => c.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            None => Vec::new(),
+        };
+        Box::new(kvs.into_iter().map(Ok))
+    }
+
+    fn clear(&self, column: Column) -> Result<()> {
+        self.columns.lock().unwrap().remove(&column);
+        Ok(())
+    }
+
+    fn write(&self, changes: Vec<Change>) -> Result<()> {
+        let mut columns = self.columns.lock().unwrap();
+        for (column, key, value) in changes {
+            let c = columns.entry(column).or_default();
+            match value {
+                Some(value) => c.insert(key, value),
+                None => c.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+type Writes = BTreeMap<(Column, Vec<u8>), Option<Vec<u8>>>;
+
+/// Batch collects writes to a store and commits them in one atomic write
+///
+/// Reads through a Batch see its writes, so it can stand in for the store while
+/// a change is built up. Nothing reaches the store before Commit.
+pub struct Batch {
+    store: Arc<dyn Store>,
+    writes: Mutex<Writes>,
+}
+
+impl Batch {
+    pub fn new(store: Arc<dyn Store>) -> Batch {
+        Batch {
+            store,
+            writes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Commit writes the batch to its store
+    pub fn commit(self) -> Result<()> {
+        let changes = self.writes.into_inner().unwrap();
+        self.store.write(
+            changes
+                .into_iter()
+                .map(|((column, key), value)| (column, key, value))
+                .collect(),
+        )
+    }
+}
+
+impl Store for Batch {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.lock().unwrap().get(&(column, key.to_vec())) {
+            return Ok(value.clone());
+        }
+        self.store.get(column, key)
+    }
+
+    fn insert(&self, column: Column, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        writes.insert((column, key.to_vec()), Some(value));
+        Ok(())
+    }
+
+    fn remove(&self, column: Column, key: &[u8]) -> Result<()> {
+        self.writes
+            .lock()
+            .unwrap()
+            .insert((column, key.to_vec()), None);
+        Ok(())
+    }
+
+    fn iter(&self, column: Column) -> StoreIter<'_> {
+        let mut kvs = BTreeMap::new();
+        for kv in self.store.iter(column) {
+            match kv {
+                Ok((k, v)) => kvs.insert(k, v),
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            };
+        }
+        let writes = self.writes.lock().unwrap();
+        let column_writes = writes
+            .range((column, Vec::new())..)
+            .take_while(|((c, _), _)| *c == column);
+        for ((_, key), value) in column_writes {
+            if let Some(value) = value {
+                kvs.insert(key.clone(), value.clone());
+            } else {
+                kvs.remove(key);
+            }
+        }
+        Box::new(kvs.into_iter().map(Ok))
+    }
+
+    fn clear(&self, column: Column) -> Result<()> {
+        let keys: Vec<Vec<u8>> = self
+            .iter(column)
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<_>>()?;
+        for key in keys {
+            self.remove(column, &key)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, changes: Vec<Change>) -> Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        for (column, key, value) in changes {
+            writes.insert((column, key), value);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// TempPath returns a path in the temporary directory that no other test, nor another
+/// run of the same test, uses
+#[cfg(test)]
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    let unique = format!("{}_{}_{}", name, std::process::id(), rand::random::<u64>());
+    std::env::temp_dir().join(format!("blockchain_rust_test_{}", unique))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_store(store: &dyn Store) {
+        store.insert(Column::Utxos, b"b", vec![2]).unwrap();
+        store.insert(Column::Utxos, b"a", vec![1]).unwrap();
+        store.insert(Column::Undo, b"a", vec![3]).unwrap();
+        assert_eq!(store.get(Column::Utxos, b"a").unwrap(), Some(vec![1]));
+        assert_eq!(store.get(Column::Headers, b"a").unwrap(), None);
+
+        let kvs: Vec<_> = store.iter(Column::Utxos).map(|kv| kv.unwrap()).collect();
+        assert_eq!(
+            kvs,
+            vec![(b"a".to_vec(), vec![1]), (b"b".to_vec(), vec![2])]
+        );
+
+        store.remove(Column::Utxos, b"a").unwrap();
+        assert_eq!(store.get(Column::Utxos, b"a").unwrap(), None);
+        store.clear(Column::Utxos).unwrap();
+        assert_eq!(store.iter(Column::Utxos).count(), 0);
+        assert_eq!(store.get(Column::Undo, b"a").unwrap(), Some(vec![3]));
+
+        assert_eq!(store.get_tip().unwrap(), "");
+        store.put_tip("hash").unwrap();
+        assert_eq!(store.get_tip().unwrap(), "hash");
+
+        let changes = vec![
+            (Column::Utxos, b"e".to_vec(), Some(vec![5])),
+            (Column::Undo, b"a".to_vec(), None),
+        ];
+        store.write(changes).unwrap();
+        assert_eq!(store.get(Column::Utxos, b"e").unwrap(), Some(vec![5]));
+        assert_eq!(store.get(Column::Undo, b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_batch() {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        store.insert(Column::Utxos, b"a", vec![1]).unwrap();
+        store.insert(Column::Utxos, b"b", vec![2]).unwrap();
+
+        let batch = Batch::new(store.clone());
+        batch.remove(Column::Utxos, b"a").unwrap();
+        batch.insert(Column::Utxos, b"c", vec![3]).unwrap();
+        batch.insert(Column::Undo, b"d", vec![4]).unwrap();
+        assert_eq!(batch.get(Column::Utxos, b"a").unwrap(), None);
+        let kvs: Vec<_> = batch.iter(Column::Utxos).map(|kv| kv.unwrap()).collect();
+        assert_eq!(
+            kvs,
+            vec![(b"b".to_vec(), vec![2]), (b"c".to_vec(), vec![3])]
+        );
+        assert_eq!(store.get(Column::Utxos, b"a").unwrap(), Some(vec![1]));
+        assert_eq!(store.get(Column::Utxos, b"c").unwrap(), None);
+
+        batch.commit().unwrap();
+        assert_eq!(store.get(Column::Utxos, b"a").unwrap(), None);
+        assert_eq!(store.get(Column::Utxos, b"c").unwrap(), Some(vec![3]));
+        assert_eq!(store.get(Column::Undo, b"d").unwrap(), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&MemoryStore::default());
+    }
+
+    #[test]
+    fn test_sled_store() {
+        let path = temp_path("sled_store");
+        check_store(&SledStore::open(&path).unwrap());
+        std::fs::remove_dir_all(&path).ok();
+    }
+}
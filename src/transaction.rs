@@ -1,6 +1,7 @@
 //! transaction implement
 
 use super::*;
+use crate::amount::Amount;
 use crate::utxoset::*;
 use crate::wallet::*;
 use bincode::serialize;
@@ -11,11 +12,42 @@ use crypto::sha2::Sha256;
 use failure::format_err;
 use log::{debug, error, info};
 use rand::rngs::OsRng;
-use rand::{Rng, RngCore};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-const SUBSIDY: i32 = 10;
+/// INITIAL_SUBSIDY is what a coinbase may create on top of fees before the first halving
+pub const INITIAL_SUBSIDY: Amount = Amount::from_coins(10);
+/// HALVING_INTERVAL is the number of blocks after which the subsidy is cut in half
+pub const HALVING_INTERVAL: i32 = 210_000;
+
+/// BlockSubsidy returns the amount a coinbase at height may create on top of fees
+pub fn block_subsidy(height: i32) -> Amount {
+    let halvings = height / HALVING_INTERVAL;
+    if !(0..64).contains(&halvings) {
+        return Amount::ZERO;
+    }
+    Amount::from_units(INITIAL_SUBSIDY.units() >> halvings)
+}
+
+/// TotalSupply returns the amount created by the subsidies of blocks 0 to height
+///
+/// The subsidy halves every HALVING_INTERVAL blocks, so the supply never exceeds
+/// twice INITIAL_SUBSIDY per interval.
+pub fn total_supply(height: i32) -> Amount {
+    let mut supply: u64 = 0;
+    let mut halvings = 0;
+    while halvings < 64 {
+        let start = halvings as i64 * HALVING_INTERVAL as i64;
+        if start > height as i64 {
+            break;
+        }
+        let blocks = (height as i64 - start + 1).min(HALVING_INTERVAL as i64) as u64;
+        supply += (INITIAL_SUBSIDY.units() >> halvings) * blocks;
+        halvings += 1;
+    }
+    Amount::from_units(supply)
+}
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -29,16 +61,10 @@ pub struct TXInput {
 /// TXOutput represents a transaction output
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
-    pub value: i32,
+    pub value: Amount,
     pub pub_key_hash: Vec<u8>,
 }
 
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
-}
-
 /// Transaction represents a Bitcoin transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
@@ -49,7 +75,16 @@ pub struct Transaction {
 
 impl Transaction {
     /// NewUTXOTransaction creates a new transaction
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
+    ///
+    /// The inputs cover amount plus fee; the fee is left out of the outputs for the miner.
+    #[allow(non_snake_case)]
+    pub fn new_UTXO(
+        wallet: &Wallet,
+        to: &str,
+        amount: Amount,
+        fee: Amount,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
         info!(
             "new UTXO Transaction from: {} to: {}",
             wallet.get_address(),
@@ -60,9 +95,15 @@ impl Transaction {
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        if amount == Amount::ZERO {
+            return Err(format_err!("Amount must be positive"));
+        }
+        let total = amount
+            .checked_add(fee)
+            .ok_or_else(|| format_err!("Amount plus fee is too large"))?;
+        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, total)?;
 
-        if acc_v.0 < amount {
+        if acc_v.0 < total {
             error!("Not Enough balance");
             return Err(format_err!(
                 "Not Enough balance: current balance {}",
@@ -83,8 +124,9 @@ impl Transaction {
         }
 
         let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+        if acc_v.0 > total {
+            let change = acc_v.0.checked_sub(total).unwrap();
+            vout.push(TXOutput::new(change, wallet.get_address())?)
         }
 
         let mut tx = Transaction {
@@ -99,15 +141,27 @@ impl Transaction {
     }
 
     /// NewCoinbaseTX creates a new coinbase transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    ///
+    /// It pays the subsidy of the block at height plus the fees of its other transactions.
+    /// The height is committed at the start of the input data, so coinbase ids are unique.
+    pub fn new_coinbase(
+        to: String,
+        mut data: String,
+        height: i32,
+        fees: Amount,
+    ) -> Result<Transaction> {
         info!("new coinbase Transaction to: {}", to);
         let mut key: [u8; 32] = [0; 32];
         if data.is_empty() {
-            let mut rand = OsRng::default();
+            let mut rand = OsRng;
             rand.fill_bytes(&mut key);
             data = format!("Reward to '{}'", to);
         }
-        let mut pub_key = Vec::from(data.as_bytes());
+        let reward = block_subsidy(height)
+            .checked_add(fees)
+            .ok_or_else(|| format_err!("Coinbase reward is too large"))?;
+        let mut pub_key = Vec::from(height.to_le_bytes());
+        pub_key.append(&mut Vec::from(data.as_bytes()));
         pub_key.append(&mut Vec::from(key));
 
         let mut tx = Transaction {
@@ -118,7 +172,7 @@ impl Transaction {
                 signature: Vec::new(),
                 pub_key,
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(reward, to)?],
         };
         tx.id = tx.hash()?;
         Ok(tx)
@@ -129,7 +183,61 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
+    /// Touches checks whether the transaction pays to or spends from the public key hash
+    pub fn touches(&self, pub_key_hash: &[u8]) -> bool {
+        let spends = !self.is_coinbase() && self.vin.iter().any(|vin| vin.uses_key(pub_key_hash));
+        spends
+            || self
+                .vout
+                .iter()
+                .any(|out| out.is_locked_with_key(pub_key_hash))
+    }
+
+    /// CoinbaseHeight returns the block height committed in a coinbase
+    pub fn coinbase_height(&self) -> Option<i32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let data = self.vin[0].pub_key.get(..4)?;
+        Some(i32::from_le_bytes(data.try_into().ok()?))
+    }
+
+    /// ValueOut returns the total value of the outputs
+    pub fn value_out(&self) -> Result<Amount> {
+        Amount::checked_sum(self.vout.iter().map(|out| out.value))
+            .ok_or_else(|| format_err!("ERROR: Transaction outputs exceed the maximum amount"))
+    }
+
+    /// Fee returns how much the inputs pay on top of the outputs
+    #[allow(non_snake_case)]
+    pub fn fee(&self, prev_TXs: &HashMap<String, Transaction>) -> Result<Amount> {
+        if self.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+
+        let mut values = Vec::new();
+        for vin in &self.vin {
+            let prev_Tx = match prev_TXs.get(&vin.txid) {
+                Some(tx) => tx,
+                None => return Err(format_err!("ERROR: Previous transaction is not found")),
+            };
+            match prev_Tx.vout.get(vin.vout as usize) {
+                Some(out) if vin.vout >= 0 => values.push(out.value),
+                _ => return Err(format_err!("ERROR: Previous output is not found")),
+            }
+        }
+        let value_in = Amount::checked_sum(values)
+            .ok_or_else(|| format_err!("ERROR: Transaction inputs exceed the maximum amount"))?;
+        value_in
+            .checked_sub(self.value_out()?)
+            .ok_or_else(|| format_err!("ERROR: Transaction outputs exceed its inputs"))
+    }
+
     /// Verify verifies signatures of Transaction inputs
+    ///
+    /// Each input must be signed by the key the output it spends is locked with, and the
+    /// outputs must not add up to more than the inputs.
+    #[allow(non_snake_case)]
     pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
@@ -141,19 +249,25 @@ impl Transaction {
             }
         }
 
+        if self.fee(&prev_TXs).is_err() {
+            return Ok(false);
+        }
+
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..self.vin.len() {
             let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+            let prev_out = &prev_Tx.vout[self.vin[in_id].vout as usize];
+            if !self.vin[in_id].uses_key(&prev_out.pub_key_hash) {
+                return Ok(false);
+            }
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
+            tx_copy.vin[in_id].pub_key = prev_out.pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
             if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
+                tx_copy.id.as_bytes(),
                 &self.vin[in_id].pub_key,
                 &self.vin[in_id].signature,
             ) {
@@ -165,6 +279,7 @@ impl Transaction {
     }
 
     /// Sign signs each input of a Transaction
+    #[allow(non_snake_case)]
     pub fn sign(
         &mut self,
         private_key: &[u8],
@@ -207,6 +322,15 @@ impl Transaction {
         Ok(hasher.result_str())
     }
 
+    /// CheckID checks the id is the hash of the transaction without its signatures
+    pub fn check_id(&self) -> Result<bool> {
+        let mut unsigned = self.clone();
+        for vin in &mut unsigned.vin {
+            vin.signature.clear();
+        }
+        Ok(unsigned.hash()? == self.id)
+    }
+
     /// TrimmedCopy creates a trimmed copy of Transaction to be used in signing
     fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::new();
@@ -215,7 +339,7 @@ impl Transaction {
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
+                vout: v.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
             })
@@ -236,6 +360,15 @@ impl Transaction {
     }
 }
 
+impl TXInput {
+    /// UsesKey checks whether the input is unlocked by the key with this hash
+    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
+        let mut hash = self.pub_key.clone();
+        hash_pub_key(&mut hash);
+        hash == pub_key_hash
+    }
+}
+
 impl TXOutput {
     /// IsLockedWithKey checks if the output can be used by the owner of the pubkey
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -249,7 +382,7 @@ impl TXOutput {
         Ok(())
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
+    pub fn new(value: Amount, address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
@@ -262,20 +395,40 @@ impl TXOutput {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::store::temp_path;
 
     #[test]
     fn test_signature() {
-        let mut ws = Wallets::new().unwrap();
+        let datadir = temp_path("signature");
+        let mut ws = Wallets::new(&datadir).unwrap();
         let wa1 = ws.create_wallet();
         let w = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
         drop(ws);
 
         let data = String::from("test");
-        let tx = Transaction::new_coinbase(wa1, data).unwrap();
+        let tx = Transaction::new_coinbase(wa1, data, 7, Amount::ZERO).unwrap();
         assert!(tx.is_coinbase());
+        assert_eq!(tx.coinbase_height(), Some(7));
 
         let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
         assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
+        std::fs::remove_dir_all(&datadir).ok();
+    }
+
+    #[test]
+    fn test_subsidy() {
+        assert_eq!(block_subsidy(0), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), Amount::from_coins(5));
+        assert_eq!(block_subsidy(HALVING_INTERVAL * 64), Amount::ZERO);
+
+        assert_eq!(total_supply(-1), Amount::ZERO);
+        assert_eq!(total_supply(0), INITIAL_SUBSIDY);
+        assert_eq!(
+            total_supply(HALVING_INTERVAL),
+            Amount::from_coins(10 * HALVING_INTERVAL as u64 + 5)
+        );
+        assert!(total_supply(i32::MAX) < Amount::from_coins(20 * HALVING_INTERVAL as u64));
     }
 }
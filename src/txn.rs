@@ -1,11 +1,9 @@
+use crate::amount::Amount;
 use crate::errors::Result;
-use crate::transaction::Transaction;
 use crate::wallet::hash_pub_key;
 use bitcoincash_addr::Address;
-use failure::format_err;
 use log::debug;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 // TXOutputs collects TXOutput
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
@@ -24,7 +22,7 @@ pub struct TXInput {
 /// TXOutput represents a transaction output
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
-    pub value: i32,
+    pub value: Amount,
     pub pub_key_hash: Vec<u8>,
 }
 impl TXInput {
@@ -50,7 +48,7 @@ impl TXOutput {
         Ok(())
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
+    pub fn new(value: Amount, address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
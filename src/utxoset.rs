@@ -1,132 +1,814 @@
 //! unspend transaction output set
 
 use super::*;
+use crate::amount::Amount;
 use crate::block::*;
 use crate::blockchain::*;
+use crate::errors::BlockError;
+use crate::store::{Batch, ChainStore, Column, Store, UtxoStore};
 use crate::transaction::*;
-use bincode::{deserialize, serialize};
-use sled;
-use std::collections::HashMap;
+use failure::format_err;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// COINBASE_MATURITY is the number of blocks a coinbase output waits before it can be spent
+pub const COINBASE_MATURITY: i32 = 10;
 
 /// UTXOSet represents UTXO set
 pub struct UTXOSet {
     pub blockchain: Blockchain,
 }
 
+/// UTXO is an unspent output along with the transaction that created it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UTXO {
+    pub output: TXOutput,
+    pub is_coinbase: bool,
+    pub height: i32,
+}
+
+/// SpentOutput is an output a block removed from the UTXO set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub utxo: UTXO,
+}
+
+/// BlockUndo keeps what is needed to disconnect a block from the UTXO set
+///
+/// `spent` holds one entry per transaction input, in block order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
+}
+
+/// AddressEvent is an output paying to an address, or the spending of one
+///
+/// `spent_by` is None for the output funding the address, or the id of the
+/// transaction spending it. `height` is the height of the block it happened in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddressEvent {
+    pub txid: String,
+    pub vout: i32,
+    pub value: Amount,
+    pub height: i32,
+    pub spent_by: Option<String>,
+}
+
+impl UTXO {
+    /// IsMatureAt checks the output can be spent in a block at height
+    ///
+    /// Coinbase outputs have to wait COINBASE_MATURITY blocks. The genesis coinbase is
+    /// exempt, so a new chain has coins to spend: this is a consensus rule, as a node
+    /// without the exemption would reject the blocks of every other node spending them.
+    pub fn is_mature_at(&self, height: i32) -> bool {
+        !self.is_coinbase || self.height == 0 || height - self.height >= COINBASE_MATURITY
+    }
+}
+
 impl UTXOSet {
+    /// New opens the UTXO set of a blockchain, repairing it if it is not at the tip
+    pub fn new(blockchain: Blockchain) -> Result<UTXOSet> {
+        let utxo_set = UTXOSet { blockchain };
+        utxo_set.repair()?;
+        Ok(utxo_set)
+    }
+
+    /// Store returns where the UTXO set is kept, the store of the blockchain
+    fn store(&self) -> &dyn Store {
+        self.blockchain.get_store()
+    }
+
     /// FindUnspentTransactions returns a list of transactions containing unspent outputs
+    ///
+    /// Only outputs that can be spent in the next block are used.
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+        amount: Amount,
+    ) -> Result<(Amount, HashMap<String, Vec<i32>>)> {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
-                }
+        let mut accumulated = Amount::ZERO;
+        let height = self.blockchain.get_best_height()? + 1;
+
+        for (txid, vout, utxo) in self.list_unspent(pub_key_hash)? {
+            if accumulated >= amount {
+                break;
+            }
+            if !utxo.is_mature_at(height) {
+                continue;
             }
+            accumulated = accumulated
+                .checked_add(utxo.output.value)
+                .ok_or_else(|| format_err!("Balance exceeds the maximum amount"))?;
+            unspent_outputs.entry(txid).or_default().push(vout);
         }
 
         Ok((accumulated, unspent_outputs))
     }
 
     /// FindUTXO finds UTXO for a public key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
-
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+    #[allow(non_snake_case)]
+    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<Vec<UTXO>> {
+        Ok(self
+            .list_unspent(pub_key_hash)?
+            .into_iter()
+            .map(|(_, _, utxo)| utxo)
+            .collect())
+    }
 
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+    /// ListUnspent returns the unspent outputs of a public key hash with their txid and vout
+    ///
+    /// The address index is used if it is kept, otherwise the whole UTXO set is scanned.
+    pub fn list_unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, UTXO)>> {
+        let mut utxos = Vec::new();
+        if self.store().has_addrindex()? {
+            let events = self.store().get_address_events(pub_key_hash)?;
+            let spent: HashSet<(&str, i32)> = events
+                .iter()
+                .filter(|event| event.spent_by.is_some())
+                .map(|event| (event.txid.as_str(), event.vout))
+                .collect();
+            for event in &events {
+                if event.spent_by.is_some() || spent.contains(&(event.txid.as_str(), event.vout)) {
+                    continue;
+                }
+                if let Some(utxo) = self.find_output(&event.txid, event.vout)? {
+                    utxos.push((event.txid.clone(), event.vout, utxo));
                 }
             }
+            return Ok(utxos);
         }
 
+        for kv in self.store().iter_utxos() {
+            let (txid, vout, utxo) = kv?;
+            if utxo.output.is_locked_with_key(pub_key_hash) {
+                utxos.push((txid, vout, utxo));
+            }
+        }
         Ok(utxos)
     }
 
+    /// GetHistory returns the outputs funding a public key hash and their spending,
+    /// oldest first
+    ///
+    /// It needs the address index.
+    pub fn get_history(&self, pub_key_hash: &[u8]) -> Result<Vec<AddressEvent>> {
+        if !self.store().has_addrindex()? {
+            return Err(format_err!(
+                "Address index is not kept, enable it with --addrindex"
+            ));
+        }
+        self.store().get_address_events(pub_key_hash)
+    }
+
+    /// EnableAddrIndex starts keeping the address index, building it for the active chain
+    ///
+    /// The index is built from the blocks and their undo data. Once enabled, it is kept
+    /// up to date as blocks are connected and disconnected.
+    pub fn enable_addrindex(&self) -> Result<()> {
+        if self.store().has_addrindex()? {
+            return Ok(());
+        }
+        info!("build the address index");
+        let batch = self.blockchain.batch();
+        for height in 0..=self.blockchain.get_best_height()? {
+            let block = self.blockchain.get_block_by_height(height)?;
+            let undo = match batch.get_undo(&block.get_hash())? {
+                Some(undo) => undo,
+                None => return Err(format_err!("ERROR: Undo data is not found")),
+            };
+            write_address_events(&batch, &block, &undo, true)?;
+        }
+        batch.put_addrindex()?;
+        batch.commit()
+    }
+
+    /// GetBalance returns the mature and immature balance of a public key hash
+    ///
+    /// Mature outputs can be spent in the next block, immature ones are coinbase
+    /// outputs still waiting for COINBASE_MATURITY blocks.
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<(Amount, Amount)> {
+        let height = self.blockchain.get_best_height()? + 1;
+        let (mature, immature): (Vec<UTXO>, Vec<UTXO>) = self
+            .find_UTXO(pub_key_hash)?
+            .into_iter()
+            .partition(|utxo| utxo.is_mature_at(height));
+
+        let sum = |utxos: Vec<UTXO>| {
+            Amount::checked_sum(utxos.iter().map(|utxo| utxo.output.value))
+                .ok_or_else(|| format_err!("Balance exceeds the maximum amount"))
+        };
+        Ok((sum(mature)?, sum(immature)?))
+    }
+
+    /// FindOutput returns the unspent output vout of a transaction, if there is one
+    pub fn find_output(&self, txid: &str, vout: i32) -> Result<Option<UTXO>> {
+        self.store().get_utxo(txid, vout)
+    }
+
+    /// VerifyTransaction checks a transaction can be included in the next block
+    ///
+    /// Every input has to spend a mature unspent output and carry a valid signature.
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
+        if tx.is_coinbase() {
+            return Ok(false);
+        }
+        let height = self.blockchain.get_best_height()? + 1;
+        for vin in &tx.vin {
+            match self.find_output(&vin.txid, vin.vout)? {
+                Some(utxo) if utxo.is_mature_at(height) => {}
+                _ => return Ok(false),
+            }
+        }
+        self.blockchain.verify_transacton(tx)
+    }
+
     /// CountTransactions returns the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
-        let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
+        let mut txids = HashSet::new();
+        for kv in self.store().iter_utxos() {
+            let (txid, _, _) = kv?;
+            txids.insert(txid);
+        }
+        Ok(txids.len() as i32)
+    }
+
+    /// GetSupply returns the coins in the UTXO set, the supply that actually exists
+    ///
+    /// It falls short of the subsidy schedule by whatever coinbases left unclaimed.
+    pub fn get_supply(&self) -> Result<Amount> {
+        let mut supply = Amount::ZERO;
+        for kv in self.store().iter_utxos() {
+            let (_, _, utxo) = kv?;
+            supply = supply
+                .checked_add(utxo.output.value)
+                .ok_or_else(|| format_err!("Supply exceeds the maximum amount"))?;
         }
-        Ok(counter)
+        Ok(supply)
     }
 
     /// Reindex rebuilds the UTXO set
+    ///
+    /// Blocks are replayed from the genesis block, which rewrites their undo data and,
+    /// if it is kept, the address index too.
+    /// Each block is committed on its own, so an interrupted reindex is finished by
+    /// Repair.
     pub fn reindex(&self) -> Result<()> {
-        std::fs::remove_dir_all("data/utxos").ok();
-        let db = sled::open("data/utxos")?;
+        let batch = self.blockchain.batch();
+        batch.clear(Column::Utxos)?;
+        batch.clear(Column::AddrIndex)?;
+        batch.put_utxo_tip("")?;
+        batch.commit()?;
 
-        let utxos = self.blockchain.find_UTXO();
+        let mut hashs = self.blockchain.get_block_hashs();
+        hashs.reverse();
+        for hash in hashs {
+            let batch = self.blockchain.batch();
+            self.update(&batch, &self.blockchain.get_block(&hash)?)?;
+            batch.commit()?;
+        }
 
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+        Ok(())
+    }
+
+    /// Repair brings a UTXO set that is not at the tip of the blockchain back to it
+    ///
+    /// Blocks are connected atomically with the tip, so this only happens after an
+    /// interrupted reindex or with a store written before the UTXO set kept its tip.
+    pub fn repair(&self) -> Result<()> {
+        let utxo_tip = self.store().get_utxo_tip()?;
+        if utxo_tip == self.blockchain.tip {
+            return Ok(());
+        }
+        warn!(
+            "UTXO set is at {:?} instead of the tip {}, repairing it",
+            utxo_tip, self.blockchain.tip
+        );
+        if utxo_tip.is_empty() || !self.blockchain.has_block(&utxo_tip)? {
+            return self.reindex();
         }
 
+        let fork = self.blockchain.find_fork(&utxo_tip, &self.blockchain.tip)?;
+        for block in &fork.disconnected {
+            let batch = self.blockchain.batch();
+            self.disconnect(&batch, block)?;
+            batch.commit()?;
+        }
+        for block in &fork.connected {
+            let batch = self.blockchain.batch();
+            self.update(&batch, block)?;
+            batch.commit()?;
+        }
         Ok(())
     }
 
     /// Update updates the UTXO set with transactions from the Block
     ///
-    /// The Block is considered to be the tip of a blockchain
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+    /// The Block is considered to be the tip of a blockchain. The changes, the block's
+    /// undo data, its transaction index entries and the new UTXO tip are written to batch.
+    pub fn update(&self, batch: &Batch, block: &Block) -> Result<()> {
+        let mut undo = BlockUndo::default();
 
         for tx in block.get_transaction() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
+                    let utxo = match batch.get_utxo(&vin.txid, vin.vout)? {
+                        Some(utxo) => utxo,
+                        None => return Err(format_err!("ERROR: Spent output is not in UTXO set")),
                     };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
+                    batch.remove_utxo(&vin.txid, vin.vout)?;
+                    undo.spent.push(SpentOutput {
+                        txid: vin.txid.clone(),
+                        vout: vin.vout,
+                        utxo,
+                    });
+                }
+            }
 
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
+            for (vout, output) in tx.vout.iter().enumerate() {
+                let utxo = UTXO {
+                    output: output.clone(),
+                    is_coinbase: tx.is_coinbase(),
+                    height: block.get_height(),
+                };
+                batch.put_utxo(&tx.id, vout as i32, &utxo)?;
+            }
+        }
+        self.blockchain.index_transactions(batch, block, true)?;
+        if batch.has_addrindex()? {
+            write_address_events(batch, block, &undo, true)?;
+        }
+        batch.put_undo(&block.get_hash(), &undo)?;
+        batch.put_utxo_tip(&block.get_hash())
+    }
+
+    /// Disconnect reverts the changes Update made for the Block
+    ///
+    /// The Block has to be the tip of the UTXO set. Spent outputs are restored
+    /// from the block's undo data, so this only touches what the block changed.
+    pub fn disconnect(&self, batch: &Batch, block: &Block) -> Result<()> {
+        let mut undo = match batch.get_undo(&block.get_hash())? {
+            Some(undo) => undo,
+            None => return Err(format_err!("ERROR: Undo data is not found")),
+        };
+        if batch.has_addrindex()? {
+            write_address_events(batch, block, &undo, false)?;
+        }
+
+        for tx in block.get_transaction().iter().rev() {
+            for vout in 0..tx.vout.len() {
+                batch.remove_utxo(&tx.id, vout as i32)?;
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for _ in &tx.vin {
+                let spent = match undo.spent.pop() {
+                    Some(spent) => spent,
+                    None => return Err(format_err!("ERROR: Undo data does not match block")),
+                };
+                batch.put_utxo(&spent.txid, spent.vout, &spent.utxo)?;
+            }
+        }
+        self.blockchain.index_transactions(batch, block, false)?;
+        batch.remove_undo(&block.get_hash())?;
+        batch.put_utxo_tip(&block.get_prev_hash())
+    }
+
+    /// ConnectBlock validates a block on top of the tip and makes it the new tip
+    ///
+    /// The block, if it is not stored yet, its UTXO changes, undo data and the new tip
+    /// are committed in one batch, so a crash leaves the block either fully connected
+    /// or not at all.
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        self.blockchain.validate_block(block, self)?;
+        let batch = self.blockchain.batch();
+        if !self.blockchain.has_block(&block.get_hash())? {
+            self.blockchain.put_block(&batch, block)?;
+        }
+        self.update(&batch, block)?;
+        self.blockchain.move_tip(&batch, &block.get_hash())?;
+        self.blockchain.commit(batch)
+    }
+
+    /// DisconnectBlock moves the tip back to the parent of the block at the tip
+    fn disconnect_block(&mut self, block: &Block) -> Result<()> {
+        let batch = self.blockchain.batch();
+        self.disconnect(&batch, block)?;
+        self.blockchain.move_tip(&batch, &block.get_prev_hash())?;
+        self.blockchain.commit(batch)
+    }
+
+    /// MineBlock mines a block with the provided transactions on top of the tip and connects it
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        info!("mine a new block");
+        let block = self.blockchain.new_block_template(transactions)?.mine()?;
+        if self.add_block(block.clone())?.connected.is_empty() {
+            return Err(format_err!("ERROR: Mined block is invalid"));
+        }
+        Ok(block)
+    }
+
+    /// AddBlock stores a Block and makes the chain with the most work the active one
+    ///
+    /// The UTXO set follows the active chain block by block. A block that fails
+    /// verification while being connected is marked invalid and the old tip restored.
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        let hash = block.get_hash();
+        if block.get_prev_hash() == self.blockchain.tip && !self.blockchain.has_block(&hash)? {
+            // a block on the tip is stored and connected at once
+            self.blockchain.check_block(&block)?;
+            return match self.connect_block(&block) {
+                Ok(()) => Ok(ChainUpdate {
+                    connected: vec![block],
+                    ..ChainUpdate::default()
+                }),
+                Err(e) => match e.downcast::<BlockError>() {
+                    Ok(invalid) => {
+                        warn!("block {} is invalid: {}", hash, invalid);
+                        let batch = self.blockchain.batch();
+                        self.blockchain.put_block(&batch, &block)?;
+                        self.blockchain.commit(batch)?;
+                        self.blockchain.invalidate_block(&hash)?;
+                        Ok(ChainUpdate {
+                            invalid: Some(invalid),
+                            ..ChainUpdate::default()
+                        })
                     }
+                    Err(e) => Err(e),
+                },
+            };
+        }
+
+        if !self.blockchain.add_block(block)? {
+            return Ok(ChainUpdate::default());
+        }
+        self.reorganize(&hash)
+    }
+
+    /// Reorganize makes the chain ending at new_tip the active one
+    ///
+    /// If a block of the new chain turns out invalid, or any step fails, the old chain
+    /// is connected back, so the active chain never ends up with less work than before.
+    /// Only an invalid block is reported in the update; other failures are returned once
+    /// the old tip is restored.
+    fn reorganize(&mut self, new_tip: &str) -> Result<ChainUpdate> {
+        let old_tip = self.blockchain.tip.clone();
+        let fork = self.blockchain.find_fork(&old_tip, new_tip)?;
+        info!(
+            "reorganize to {}: disconnect {} blocks, connect {} blocks",
+            new_tip,
+            fork.disconnected.len(),
+            fork.connected.len()
+        );
+
+        let mut update = ChainUpdate::default();
+        if let Err(e) = self.apply_fork(fork, &mut update) {
+            let back = self.blockchain.find_fork(&self.blockchain.tip, &old_tip)?;
+            let mut restored = ChainUpdate::default();
+            self.apply_fork(back, &mut restored)?;
+            update.append(restored);
+            update.invalid = Some(e.downcast::<BlockError>()?);
+        }
+        Ok(update)
+    }
+
+    /// ApplyFork disconnects and connects the blocks of fork one by one, recording in
+    /// update those it got through
+    ///
+    /// A block failing validation is marked invalid before the error is returned.
+    fn apply_fork(&mut self, fork: ChainUpdate, update: &mut ChainUpdate) -> Result<()> {
+        for block in fork.disconnected {
+            self.disconnect_block(&block)?;
+            update.disconnected.push(block);
+        }
+        for block in fork.connected {
+            if let Err(e) = self.connect_block(&block) {
+                if let Some(invalid) = e.downcast_ref::<BlockError>() {
+                    warn!("block {} is invalid: {}", block.get_hash(), invalid);
+                    self.blockchain.invalidate_block(&block.get_hash())?;
                 }
+                return Err(e);
             }
+            update.connected.push(block);
+        }
+        Ok(())
+    }
+}
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+/// WriteAddressEvents adds the address events of a block to the address index in batch,
+/// or removes them when the block is disconnected
+///
+/// The outputs a block spends come from its undo data. Events are removed by height,
+/// so the block has to be the tip.
+fn write_address_events(
+    batch: &Batch,
+    block: &Block,
+    undo: &BlockUndo,
+    connect: bool,
+) -> Result<()> {
+    let height = block.get_height();
+    let mut events: BTreeMap<Vec<u8>, Vec<AddressEvent>> = BTreeMap::new();
+    let mut spent = undo.spent.iter();
+    for tx in block.get_transaction() {
+        if !tx.is_coinbase() {
+            for _ in &tx.vin {
+                let spent = match spent.next() {
+                    Some(spent) => spent,
+                    None => return Err(format_err!("ERROR: Undo data does not match block")),
+                };
+                let output = &spent.utxo.output;
+                events
+                    .entry(output.pub_key_hash.clone())
+                    .or_default()
+                    .push(AddressEvent {
+                        txid: spent.txid.clone(),
+                        vout: spent.vout,
+                        value: output.value,
+                        height,
+                        spent_by: Some(tx.id.clone()),
+                    });
             }
+        }
+        for (vout, output) in tx.vout.iter().enumerate() {
+            events
+                .entry(output.pub_key_hash.clone())
+                .or_default()
+                .push(AddressEvent {
+                    txid: tx.id.clone(),
+                    vout: vout as i32,
+                    value: output.value,
+                    height,
+                    spent_by: None,
+                });
+        }
+    }
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+    for (pub_key_hash, mut new_events) in events {
+        let mut address_events = batch.get_address_events(&pub_key_hash)?;
+        if connect {
+            address_events.append(&mut new_events);
+        } else {
+            address_events.retain(|event| event.height != height);
         }
-        Ok(())
+        batch.put_address_events(&pub_key_hash, &address_events)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::wallet::{hash_pub_key, Wallet};
+
+    /// Pkh returns the public key hash of a wallet
+    fn pkh(wallet: &Wallet) -> Vec<u8> {
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        pub_key_hash
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let utxo = |is_coinbase, height| UTXO {
+            output: TXOutput {
+                value: Amount::from_coins(1),
+                pub_key_hash: Vec::new(),
+            },
+            is_coinbase,
+            height,
+        };
+        assert!(utxo(false, 5).is_mature_at(6));
+        assert!(utxo(true, 0).is_mature_at(1));
+        assert!(!utxo(true, 5).is_mature_at(5 + COINBASE_MATURITY - 1));
+        assert!(utxo(true, 5).is_mature_at(5 + COINBASE_MATURITY));
+    }
+
+    #[test]
+    fn test_genesis_coinbase_maturity() {
+        let (from, to) = (Wallet::new(), Wallet::new());
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(from.get_address(), store).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+
+        // the genesis coinbase can be spent in the very next block
+        let amount = Amount::from_coins(1);
+        let tx = Transaction::new_UTXO(&from, &to.get_address(), amount, Amount::ZERO, &utxo_set)
+            .unwrap();
+        let cbtx = coinbase(&from.get_address(), 1, "");
+        utxo_set.mine_block(vec![cbtx.clone(), tx]).unwrap();
+
+        // but no other coinbase can
+        let mut spend = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: cbtx.id.clone(),
+                vout: 0,
+                signature: Vec::new(),
+                pub_key: from.public_key.clone(),
+            }],
+            vout: vec![TXOutput::new(amount, to.get_address()).unwrap()],
+        };
+        spend.id = spend.hash().unwrap();
+        let cbtx = coinbase(&from.get_address(), 2, "");
+        let bc = &utxo_set.blockchain;
+        let bits = bc.get_next_bits(&bc.tip).unwrap();
+        let block = Block::new_block(vec![cbtx, spend], bc.tip.clone(), 2, bits).unwrap();
+        let update = utxo_set.add_block(block).unwrap();
+        assert!(matches!(update.invalid, Some(BlockError::ImmatureSpend(_))));
+    }
+
+    #[test]
+    fn test_update_and_disconnect() {
+        let (from, to) = (Wallet::new(), Wallet::new());
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(from.get_address(), store).unwrap();
+        let mut utxo_set = UTXOSet { blockchain: bc };
+        utxo_set.reindex().unwrap();
+        let genesis = utxo_set.get_balance(&pkh(&from)).unwrap();
+
+        let amount = Amount::from_coins(3);
+        let tx = Transaction::new_UTXO(&from, &to.get_address(), amount, Amount::ZERO, &utxo_set)
+            .unwrap();
+        let cbtx = coinbase(&from.get_address(), 1, "");
+        let block = utxo_set.mine_block(vec![cbtx, tx]).unwrap();
+        assert_eq!(utxo_set.get_balance(&pkh(&to)).unwrap().0, amount);
+        assert_eq!(
+            utxo_set.get_balance(&pkh(&from)).unwrap().0,
+            genesis.0.checked_sub(amount).unwrap()
+        );
+
+        // a UTXO set left behind the tip is brought back to it
+        let batch = utxo_set.blockchain.batch();
+        utxo_set.disconnect(&batch, &block).unwrap();
+        batch.commit().unwrap();
+        assert_eq!(utxo_set.get_balance(&pkh(&to)).unwrap().0, Amount::ZERO);
+        utxo_set.repair().unwrap();
+        assert_eq!(utxo_set.get_balance(&pkh(&to)).unwrap().0, amount);
+
+        utxo_set.disconnect_block(&block).unwrap();
+        assert_eq!(utxo_set.blockchain.tip, block.get_prev_hash());
+        assert_eq!(utxo_set.get_balance(&pkh(&to)).unwrap().0, Amount::ZERO);
+        assert_eq!(utxo_set.get_balance(&pkh(&from)).unwrap(), genesis);
+    }
+
+    #[test]
+    fn test_reorganize_failure() {
+        let address = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(address.clone(), store).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        let genesis = utxo_set.blockchain.tip.clone();
+        let b1 = utxo_set
+            .mine_block(vec![coinbase(&address, 1, "")])
+            .unwrap();
+        let b2 = utxo_set
+            .mine_block(vec![coinbase(&address, 2, "")])
+            .unwrap();
+
+        // without the undo data of b1 the reorganization fails half way through
+        utxo_set.store().remove_undo(&b1.get_hash()).unwrap();
+        let bits = utxo_set.blockchain.get_next_bits(&genesis).unwrap();
+        let mut prev = genesis;
+        for height in 1..4 {
+            let block =
+                Block::new_block(vec![coinbase(&address, height, "fork")], prev, height, bits)
+                    .unwrap();
+            prev = block.get_hash();
+            assert_eq!(utxo_set.add_block(block).is_err(), height == 3);
+        }
+
+        // and the old chain is connected back
+        assert_eq!(utxo_set.blockchain.tip, b2.get_hash());
+        assert_eq!(utxo_set.store().get_utxo_tip().unwrap(), b2.get_hash());
+        let b2_tx = &b2.get_transaction()[0].id;
+        assert!(utxo_set.find_output(b2_tx, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_address_index() {
+        let (from, to) = (Wallet::new(), Wallet::new());
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(from.get_address(), store).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        assert!(utxo_set.get_history(&pkh(&from)).is_err());
+
+        let amount = Amount::from_coins(3);
+        let tx = Transaction::new_UTXO(&from, &to.get_address(), amount, Amount::ZERO, &utxo_set)
+            .unwrap();
+        let cbtx = coinbase(&from.get_address(), 1, "");
+        let block = utxo_set.mine_block(vec![cbtx, tx.clone()]).unwrap();
+        let unspent = utxo_set.list_unspent(&pkh(&from)).unwrap();
+        let scanned = utxo_set
+            .blockchain
+            .get_address_proofs(&[pkh(&from), pkh(&to)])
+            .unwrap();
+        assert_eq!(scanned.len(), 3);
+
+        // the index is built for the blocks already connected and gives the same outputs
+        utxo_set.enable_addrindex().unwrap();
+        let history = utxo_set.get_history(&pkh(&to)).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].height, history[0].value), (1, amount));
+        assert_eq!(history[0].txid, tx.id);
+        let history = utxo_set.get_history(&pkh(&from)).unwrap();
+        assert_eq!(history[0].height, 0);
+        assert!(history
+            .iter()
+            .any(|event| event.spent_by == Some(tx.id.clone())));
+        let txids = |proofs: Vec<TxProof>| {
+            let mut txids: Vec<_> = proofs.into_iter().map(|p| p.transaction.id).collect();
+            txids.sort();
+            txids
+        };
+        let pub_key_hashes = [pkh(&from), pkh(&to)];
+        let proofs = utxo_set.blockchain.get_address_proofs(&pub_key_hashes);
+        assert_eq!(txids(proofs.unwrap()), txids(scanned));
+        let indexed = utxo_set.list_unspent(&pkh(&from)).unwrap();
+        let outpoints = |utxos: &[(String, i32, UTXO)]| {
+            let mut outpoints: Vec<_> = utxos
+                .iter()
+                .map(|(txid, vout, _)| (txid.clone(), *vout))
+                .collect();
+            outpoints.sort();
+            outpoints
+        };
+        assert_eq!(outpoints(&indexed), outpoints(&unspent));
+
+        // disconnecting the block takes its events out
+        utxo_set.disconnect_block(&block).unwrap();
+        assert!(utxo_set.get_history(&pkh(&to)).unwrap().is_empty());
+        let history = utxo_set.get_history(&pkh(&from)).unwrap();
+        assert!(history
+            .iter()
+            .all(|event| event.height == 0 && event.spent_by.is_none()));
+        assert_eq!(utxo_set.list_unspent(&pkh(&from)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_partial_spend() {
+        let address = Wallet::new().get_address();
+        let store = Box::new(MemoryStore::default());
+        let bc = Blockchain::create_with_store(address, store).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        let output = |coins| TXOutput {
+            value: Amount::from_coins(coins),
+            pub_key_hash: Vec::new(),
+        };
+        let spend = |id: &str, txid: &str, vout| Transaction {
+            id: id.to_string(),
+            vin: vec![TXInput {
+                txid: txid.to_string(),
+                vout,
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+            }],
+            vout: vec![output(9)],
+        };
+        let funding = Transaction {
+            id: String::from("funding"),
+            vin: Vec::new(),
+            vout: vec![output(1), output(2), output(3)],
+        };
+
+        let mut blocks = Vec::new();
+        let mut prev = utxo_set.blockchain.tip.clone();
+        for (height, tx) in [funding, spend("a", "funding", 0), spend("b", "funding", 2)]
+            .into_iter()
+            .enumerate()
+        {
+            let mut block = Block::new_template(vec![tx], prev, height as i32 + 1, 0).unwrap();
+            block.seal().unwrap();
+            let batch = utxo_set.blockchain.batch();
+            utxo_set.update(&batch, &block).unwrap();
+            batch.commit().unwrap();
+            prev = block.get_hash();
+            blocks.push(block);
+        }
+
+        // outputs keep their vout once others of the same transaction are spent
+        let value = |vout| {
+            let utxo = utxo_set.find_output("funding", vout).unwrap();
+            utxo.map(|utxo| utxo.output.value)
+        };
+        assert_eq!(value(0), None);
+        assert_eq!(value(1), Some(Amount::from_coins(2)));
+        assert_eq!(value(2), None);
+        assert_eq!(utxo_set.count_transactions().unwrap(), 4);
+
+        for block in blocks[1..].iter().rev() {
+            let batch = utxo_set.blockchain.batch();
+            utxo_set.disconnect(&batch, block).unwrap();
+            batch.commit().unwrap();
+        }
+        assert_eq!(value(0), Some(Amount::from_coins(1)));
+        assert_eq!(value(2), Some(Amount::from_coins(3)));
+        assert!(utxo_set.find_output("a", 0).unwrap().is_none());
+        let funded = Amount::from_coins(6).checked_add(block_subsidy(0));
+        assert_eq!(Some(utxo_set.get_supply().unwrap()), funded);
     }
 }
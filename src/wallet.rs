@@ -7,6 +7,7 @@ use log::info;
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
@@ -40,6 +41,12 @@ impl Wallet {
     }
 }
 
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
@@ -52,19 +59,22 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
 
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    path: PathBuf,
 }
 
 impl Wallets {
-    pub fn new() -> Result<Wallets> {
+    /// NewWallets loads the wallets kept in datadir
+    pub fn new(datadir: &Path) -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            path: datadir.join("wallets"),
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&wlt.path)?;
         for item in db.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
+            let wallet = bincode::deserialize(&i.1)?;
             wlt.wallets.insert(address, wallet);
         }
         drop(db);
@@ -81,7 +91,7 @@ impl Wallets {
 
     pub fn get_all_addresses(&self) -> Vec<String> {
         let mut addresses = Vec::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone())
         }
         addresses
@@ -92,7 +102,7 @@ impl Wallets {
     }
 
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&self.path)?;
 
         for (address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;
@@ -0,0 +1,182 @@
+//! P2P wire framing
+//!
+//! Every message goes over the wire as a frame: a header of the network magic, the
+//! command padded with zeros to CMD_LEN bytes, the payload length and a checksum,
+//! followed by the payload. Frames follow each other on a connection, so one
+//! connection carries any number of messages.
+
+use crate::errors::{Result, WireError};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::io::{ErrorKind, Read, Write};
+
+/// MAGIC starts every frame, telling apart the nodes of this network
+pub const MAGIC: [u8; 4] = [0xd9, 0xb4, 0xbe, 0xf9];
+/// CMD_LEN is the length of the command field
+pub const CMD_LEN: usize = 12;
+/// HEADER_LEN is the length of a frame header: magic, command, length and checksum
+pub const HEADER_LEN: usize = 4 + CMD_LEN + 4 + 4;
+/// MAX_PAYLOAD is the largest payload a frame can carry
+pub const MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
+/// PAYLOAD_CHUNK is how much room is made for a payload before any of it arrives
+const PAYLOAD_CHUNK: u32 = 64 * 1024;
+
+/// Checksum returns the first four bytes of the double SHA-256 of the payload
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut hash = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut hash);
+    let mut hasher = Sha256::new();
+    hasher.input(&hash);
+    hasher.result(&mut hash);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// EncodeFrame returns the frame carrying payload for cmd
+pub fn encode_frame(cmd: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    if cmd.is_empty() || cmd.len() > CMD_LEN || !cmd.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(WireError::BadCommand.into());
+    }
+    if payload.len() > MAX_PAYLOAD as usize {
+        return Err(WireError::Oversized(payload.len() as u32).into());
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    let mut command = [0; CMD_LEN];
+    command[..cmd.len()].copy_from_slice(cmd.as_bytes());
+    frame.extend_from_slice(&command);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(payload));
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// WriteFrame writes the frame carrying payload for cmd
+pub fn write_frame<W: Write>(w: &mut W, cmd: &str, payload: &[u8]) -> Result<()> {
+    w.write_all(&encode_frame(cmd, payload)?)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// ReadFrame reads the next frame, returning its command and payload
+///
+/// It returns None if the connection is closed between two frames. A frame with a
+/// bad header or checksum is rejected with a WireError; the payload of an oversized
+/// frame is never read. The payload buffer grows as the bytes come in, so a peer
+/// announcing a large payload cannot hold memory it does not send.
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Option<(String, Vec<u8>)>> {
+    let mut header = [0; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match r.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if header[..4] != MAGIC {
+        return Err(WireError::BadMagic.into());
+    }
+    let cmd = parse_command(&header[4..4 + CMD_LEN])?;
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[4 + CMD_LEN..8 + CMD_LEN]);
+    let len = u32::from_le_bytes(len);
+    if len > MAX_PAYLOAD {
+        return Err(WireError::Oversized(len).into());
+    }
+
+    let mut payload = Vec::with_capacity(len.min(PAYLOAD_CHUNK) as usize);
+    r.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    if checksum(&payload) != header[8 + CMD_LEN..] {
+        return Err(WireError::BadChecksum.into());
+    }
+    Ok(Some((cmd, payload)))
+}
+
+/// ParseCommand reads a command: printable ASCII padded with zeros, never empty
+fn parse_command(bytes: &[u8]) -> Result<String> {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    if len == 0
+        || !bytes[..len].iter().all(|b| b.is_ascii_graphic())
+        || bytes[len..].iter().any(|b| *b != 0)
+    {
+        return Err(WireError::BadCommand.into());
+    }
+    Ok(String::from_utf8(bytes[..len].to_vec())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn wire_error(result: Result<Option<(String, Vec<u8>)>>) -> WireError {
+        result.unwrap_err().downcast::<WireError>().unwrap()
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, "version", b"hello").unwrap();
+        write_frame(&mut stream, "getheaders", b"").unwrap();
+        let mut r = Cursor::new(stream.clone());
+        assert_eq!(
+            read_frame(&mut r).unwrap(),
+            Some((String::from("version"), b"hello".to_vec()))
+        );
+        assert_eq!(
+            read_frame(&mut r).unwrap(),
+            Some((String::from("getheaders"), Vec::new()))
+        );
+        assert_eq!(read_frame(&mut r).unwrap(), None);
+
+        // a connection closed in the middle of a frame is an error
+        assert!(read_frame(&mut Cursor::new(&stream[..HEADER_LEN - 1])).is_err());
+        assert!(read_frame(&mut Cursor::new(&stream[..HEADER_LEN + 2])).is_err());
+        let mut large = encode_frame("block", b"").unwrap();
+        large[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&MAX_PAYLOAD.to_le_bytes());
+        assert!(read_frame(&mut Cursor::new(large)).is_err());
+        assert!(encode_frame("commandtoolong", b"").is_err());
+        assert!(encode_frame("", b"").is_err());
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        let frame = encode_frame("block", b"payload").unwrap();
+
+        let mut bad = frame.clone();
+        bad[0] ^= 1;
+        assert_eq!(
+            wire_error(read_frame(&mut Cursor::new(bad))),
+            WireError::BadMagic
+        );
+
+        let mut bad = frame.clone();
+        bad[4 + 6] = b'x';
+        assert_eq!(
+            wire_error(read_frame(&mut Cursor::new(bad))),
+            WireError::BadCommand
+        );
+
+        let mut bad = frame.clone();
+        bad[HEADER_LEN] ^= 1;
+        assert_eq!(
+            wire_error(read_frame(&mut Cursor::new(bad))),
+            WireError::BadChecksum
+        );
+
+        let mut bad = frame;
+        bad[4 + CMD_LEN..8 + CMD_LEN].copy_from_slice(&(MAX_PAYLOAD + 1).to_le_bytes());
+        assert_eq!(
+            wire_error(read_frame(&mut Cursor::new(bad))),
+            WireError::Oversized(MAX_PAYLOAD + 1)
+        );
+    }
+}